@@ -2,18 +2,249 @@ require 'rubygems'
 require 'json'
 require 'open-uri'
 require 'uri'
+require 'fileutils'
+require 'optparse'
+require 'net/http'
+require 'cgi'
+require 'time'
+require 'digest'
+require 'zlib'
+require 'rubygems/package'
+require 'socket'
+require 'yaml'
+require 'pathname'
+require 'tmpdir'
+require 'timeout'
+require 'openssl'
+
+# Orders in which Reddit can return replies. "confidence" is what Reddit calls "best" on the web.
+COMMENT_SORTS = %w[confidence top new controversial old qa]
+
+# Levels of the messages shown, from the fewest to the most. See puts() below.
+LOG_LEVELS = %w[error warning info debug]
+
+# Command line options. All of them are optional; without any, the script prompts for the post link(s) to save.
+# Settings given on the command line (e.g. --save-dir, --set) go under :settings, and take precedence over the settings file.
+options = { :settings => {} }
+
+begin
+    OptionParser.new do |opts|
+        opts.banner = "Usage: ruby reddit_markdown.rb [options]"
+
+        opts.on("--users USERS", Array, "Save all posts submitted by the given user(s). Separate multiple users with commas, e.g. u/spez,kn0thing") do |users|
+            options[:users] = users
+        end
+
+        opts.on("--subs SUBREDDITS", Array, "Save the posts currently listed in the given subreddit(s). Separate multiple subreddits with commas, e.g. r/rust,ruby") do |subs|
+            options[:subs] = subs
+        end
+
+        opts.on("--search QUERIES", Array, "Save the newest posts matching the given Reddit search(es), across all of Reddit. Separate multiple searches with commas") do |queries|
+            options[:search] = queries
+        end
+
+        opts.on("--collections COLLECTIONS", Array, "Save the posts of the given collection(s) of subreddits, as defined under \"collections\" in settings.json") do |collections|
+            options[:collections] = collections
+        end
+
+        opts.on("--sort SORT", "The order of replies: #{COMMENT_SORTS.join(", ")}. Overrides the comment_sort setting") do |sort|
+            options[:sort] = sort
+        end
+
+        opts.on("--saved", "Save all posts and comments you have saved on Reddit. Requires the \"auth\" settings to be filled in") do
+            options[:saved] = true
+        end
+
+        opts.on("--watch MINUTES", Integer, "With --subs, --collections, or --search, keep checking the subreddits and searches for new posts every given number of minutes") do |minutes|
+            # Checking continuously would flood Reddit with requests.
+            raise OptionParser::InvalidArgument, "#{minutes} (must be at least 1 minute)" if minutes < 1
+            options[:watch] = minutes
+            options[:skip_existing] = true
+        end
+
+        opts.on("--serve PORT", Integer, "Keep running and accept posts to save through a local HTTP API on the given port (see the README)") do |port|
+            options[:serve] = port
+        end
+
+        opts.on("--skip-existing", "Skip the posts that were already saved (per the archive index), without downloading them again") do
+            options[:skip_existing] = true
+        end
+
+        opts.on("--force", "Save all posts anew, even if they were already saved (ignores --skip-existing and resync_existing_posts)") do
+            options[:force] = true
+        end
+
+        opts.on("--config PATH", "Read the settings from the given file instead of looking for settings.json (see the README)") do |config_path|
+            options[:config] = config_path
+        end
+
+        opts.on("--profile NAME", "Use the settings of the given profile, as defined under \"profiles\" in settings.json") do |profile|
+            options[:profile] = profile
+        end
+
+        opts.on("--save-dir DIRECTORY", "Save the posts to the given folder. Overrides the default_save_location setting") do |save_directory|
+            options[:settings]['default_save_location'] = save_directory
+        end
+
+        opts.on("--file-format FORMAT", %w[md txt], "The format of the saved files: md or txt. Overrides the file_format setting") do |file_format|
+            options[:settings]['file_format'] = file_format
+        end
+
+        opts.on("--reply-depth-max DEPTH", Integer, "The maximum depth of replies to save (-1 for no limit). Overrides the reply_depth_max setting") do |depth|
+            options[:settings]['reply_depth_max'] = depth
+        end
+
+        opts.on("--no-media", "Don't download images and videos. Overrides the download_media setting") do
+            options[:settings]['download_media'] = false
+        end
+
+        opts.on("--overwrite", "Overwrite existing files instead of saving under a new name. Overrides the overwrite_existing_file setting") do
+            options[:settings]['overwrite_existing_file'] = true
+        end
+
+        opts.on("--set KEY=VALUE", "Override any setting, e.g. --set show_upvotes=false or --set filters.min_upvotes=10. VALUE is read as JSON, or as a string") do |assignment|
+            key, value = assignment.split("=", 2)
+            if value == nil
+                raise OptionParser::InvalidArgument, assignment
+            end
+
+            begin
+                value = JSON.parse(value)
+            rescue JSON::ParserError
+            end
+
+            # Nested settings (e.g. filters.min_upvotes) go under their parent.
+            *parent_keys, last_key = key.split(".")
+            parent = parent_keys.reduce(options[:settings]) { |nested, parent_key| nested[parent_key] ||= {} }
+            parent[last_key] = value
+        end
+
+        opts.on("--validate-config", "Check the settings for mistakes (unknown or missing keys, invalid regexes, etc.), then exit") do
+            options[:validate_config] = true
+        end
+
+        opts.on("-q", "--quiet", "Only show warnings and errors. Twice (-qq) to only show errors") do
+            options[:verbosity] = (options[:verbosity] || 0) - 1
+        end
+
+        opts.on("-v", "--verbose", "Also show details, such as each request made") do
+            options[:verbosity] = (options[:verbosity] || 0) + 1
+        end
+
+        opts.on("--log-level LEVEL", LOG_LEVELS, "The messages to show: #{LOG_LEVELS.join(", ")} (each level includes the ones before it)") do |level|
+            options[:log_level] = level
+        end
+
+        opts.on("--events PATH", "Write progress events as JSON lines to the given file or named pipe, for other programs to follow") do |events_path|
+            options[:events] = events_path
+        end
+
+        opts.on("--record DIRECTORY", "Save every response from Reddit (and every media downloaded) into the given folder, for replaying the run later") do |record_directory|
+            options[:record] = record_directory
+        end
+
+        opts.on("--replay DIRECTORY", "Read every response from the given folder (made with --record) instead of Reddit, e.g. to reproduce a bug") do |replay_directory|
+            options[:replay] = replay_directory
+        end
+
+        opts.on("--extract PATTERN", "Also list the replies matching the given regex or keyword (case-insensitive) in an extracts file next to each saved post") do |pattern|
+            options[:extract] = pattern
+        end
+
+        opts.on("--report PATH", "After the run, write the outcome of each link (saved, skipped, or failed, with its path, size, media count, or error) to the given .json or .csv file") do |report_path|
+            options[:report] = report_path
+        end
+
+        opts.on("--prune", "Delete the saved posts that the retention settings no longer keep (with their media and archive index entries), then exit") do
+            options[:prune] = true
+        end
+
+        opts.on("--export-site DIRECTORY", "Export the saved posts (per the archive index) as a Hugo/Zola content tree into the given folder, then exit") do |site_directory|
+            options[:export_site] = site_directory
+        end
+
+        opts.on("--unsave", "With --saved, unsave each post or comment on Reddit once it has been saved locally") do
+            options[:unsave] = true
+        end
+    end.parse!
+rescue OptionParser::ParseError => e
+    puts "❌Error: #{e.message}. Run with --help to see all options. Exiting..."
+    exit
+end
+
+# Messages are shown per the log level: info by default, one level less or more per -q or -v, or as given with --log-level.
+LOG_LEVEL = options[:log_level] ? LOG_LEVELS.index(options[:log_level]) : (LOG_LEVELS.index("info") + (options[:verbosity] || 0)).clamp(0, LOG_LEVELS.length - 1)
+
+# Show a message, unless it is below the log level. Messages are told apart by how they start: "❌" for errors, "⚠️" for
+# warnings, and "🔍" for details only shown with -v. Everything else is info. Prompts ("✏️", "=>") are always shown.
+def puts(*messages)
+    text = messages.join.lstrip
+    level = if text.start_with?("✏️", "=>")
+                -1
+            elsif text.start_with?("❌")
+                LOG_LEVELS.index("error")
+            elsif text.start_with?("⚠️", "Warning:")
+                LOG_LEVELS.index("warning")
+            elsif text.start_with?("🔍")
+                LOG_LEVELS.index("debug")
+            else
+                LOG_LEVELS.index("info")
+            end
+
+    super if level <= LOG_LEVEL
+end
+
+# With --record, responses are saved as they are downloaded. With --replay, they are read from a previous recording instead,
+# so that a run can be reproduced without logging in or depending on Reddit. See with_recording().
+RECORD_DIRECTORY = options[:record]
+REPLAY_DIRECTORY = options[:replay]
+
+# With --events, progress events are written as JSON lines (see emit_event()), e.g. for a wrapper UI to show progress.
+EVENTS_IO = options[:events] ? File.open(options[:events], "a") : nil
+EVENTS_IO.sync = true if EVENTS_IO
+
+# Runs that only work on what was already saved (e.g. --export-site) don't go online, not even to check for updates or log in.
+network_needed = !options[:export_site] && !options[:prune]
+
+# Prompts can't be answered when the script isn't run from a terminal (e.g. from cron or CI, or with input piped in).
+# In that case, links are read from standard input instead, and anything else that would be prompted for is an error.
+interactive = $stdin.tty?
 
 puts "ℹ️This script saves the content (body and replies) of a Reddit post to a Markdown file for easy reading, sharing, and archiving."
 
-unless File.exist?("settings.json")
-    puts "❌Error: settings.json not found. Please get a copy of it from https://github.com/chauduyphanvu/reddit-markdown/releases. Exiting..."
+# Without --config, settings.json is looked for in the current folder, then in the user's config folder:
+# $XDG_CONFIG_HOME/reddit-markdown (~/.config/reddit-markdown by default), then %APPDATA%\reddit-markdown on Windows.
+# The settings can also be written in YAML (settings.yaml or settings.yml), which allows comments. JSON is looked for first.
+if options[:config]
+    settings_paths = [File.expand_path(options[:config])]
+else
+    settings_directories = [".", File.join(ENV["XDG_CONFIG_HOME"] || File.expand_path("~/.config"), "reddit-markdown")]
+    settings_directories << File.join(ENV["APPDATA"], "reddit-markdown") if ENV["APPDATA"]
+    settings_paths = settings_directories.flat_map { |settings_directory| %w[settings.json settings.yaml settings.yml].map { |name| File.join(settings_directory, name) } }
+end
+settings_path = settings_paths.find { |path| File.file?(path) }
+
+if settings_path == nil
+    puts "❌Error: No settings file (settings.json, settings.yaml, or settings.yml) found. Looked for one at:"
+    settings_paths.each { |path| puts "    #{File.expand_path(path)}" }
+    puts "Please get a copy of it from https://github.com/chauduyphanvu/reddit-markdown/releases, or give its path with --config. Exiting..."
+    exit
+end
+
+# TOML would need a parser from outside the standard library, which the script does without.
+if File.extname(settings_path).downcase == ".toml"
+    puts "❌Error: TOML settings files (#{settings_path}) are not supported. Write the settings in JSON or YAML instead. Exiting..."
     exit
 end
 
 begin
-    settings = JSON.parse(File.read("settings.json"))
-rescue JSON::ParserError
-    puts "❌Error: Failed to parse script settings. Ensure that settings.json is valid JSON. Exiting..."
+    if [".yaml", ".yml"].include?(File.extname(settings_path).downcase)
+        settings = YAML.safe_load(File.read(settings_path))
+    else
+        settings = JSON.parse(File.read(settings_path))
+    end
+rescue JSON::ParserError, Psych::SyntaxError
+    puts "❌Error: Failed to parse script settings. Ensure that #{settings_path} is valid #{File.extname(settings_path).delete(".").upcase}. Exiting..."
     exit
 end
 
@@ -23,32 +254,417 @@ if settings == nil || settings.empty?
     exit
 end
 
+# Override settings with others, e.g. those of a profile or given on the command line. Nested settings (e.g. filters)
+# are merged key by key, so that overriding one of them keeps the others.
+def merge_settings(settings, overrides)
+    settings.merge(overrides) { |_, value, override| value.is_a?(Hash) && override.is_a?(Hash) ? value.merge(override) : override }
+end
+
+# With --profile, the settings of the profile (e.g. its own save location, filters, and format) take precedence over
+# the rest of the settings file. The settings given on the command line (e.g. --save-dir, --set) take precedence over both.
+if options[:profile]
+    profile = (settings['profiles'] || {})[options[:profile]]
+
+    unless profile.is_a?(Hash)
+        profile_names = (settings['profiles'] || {}).keys
+        puts "❌Error: Unknown profile \"#{options[:profile]}\". Profiles defined under \"profiles\" in the settings: #{profile_names.empty? ? "none" : profile_names.join(", ")}. Exiting..."
+        exit
+    end
+
+    settings = merge_settings(settings, profile)
+    puts "ℹ️Using the \"#{options[:profile]}\" profile."
+end
+settings = merge_settings(settings, options[:settings])
+
+# Limits on how fast requests are made (see throttle()), whichever feature they are for. reddit_requests_per_minute covers every
+# request to Reddit (posts, listings, subreddit icons, logging in, and unsaving), and media_downloads_per_minute_per_host
+# every request to other websites while saving posts (media, the Imgur, Streamable, and RedGifs APIs, the comment archive,
+# and URL shorteners). The update check and webhooks are not limited, as they make at most a request each per post.
+LIMITS = settings['limits'] || {}
+
+# Open a connection to host and port through a SOCKS5 proxy (e.g. Tor's SocksPort), with the user name and password of its
+# link, if any. The host name is resolved by the proxy, so that DNS lookups don't bypass it either.
+def socks5_connect(proxy_uri, host, port)
+    socket = TCPSocket.new(proxy_uri.host, proxy_uri.port || 1080)
+    user = CGI.unescape(proxy_uri.user.to_s).b
+    password = CGI.unescape(proxy_uri.password.to_s).b
+
+    socket.write(user == "" ? [5, 1, 0].pack("C*") : [5, 2, 0, 2].pack("C*"))
+    method = socket.read(2).to_s.unpack("C*")[1]
+    if method == 2
+        socket.write([1, user.bytesize].pack("C*") + user + [password.bytesize].pack("C") + password)
+        if socket.read(2).to_s.unpack("C*")[1] != 0
+            raise "the SOCKS proxy refused the user name or password"
+        end
+    elsif method != 0
+        raise "the SOCKS proxy asks for an unsupported way of authenticating"
+    end
+
+    socket.write([5, 1, 0, 3, host.bytesize].pack("C*") + host.b + [port].pack("n"))
+    reply, address_type = socket.read(4).to_s.unpack("C*").values_at(1, 3)
+    if reply != 0
+        raise "the SOCKS proxy could not connect to #{host}:#{port} (reply #{reply.inspect})"
+    end
+
+    # The address the proxy connected from (IPv4, domain name, or IPv6) and its port, which aren't needed.
+    address_length = address_type == 3 ? socket.read(1).unpack1("C") : (address_type == 4 ? 16 : 4)
+    socket.read(address_length + 2)
+    socket
+end
+
+# Open-uri and Net::HTTP only support HTTP(S) proxies, so a SOCKS5 proxy is used through a local HTTP proxy (on a free port of
+# 127.0.0.1) that forwards each connection through it: HTTPS requests are tunneled with CONNECT, and plain HTTP requests
+# are passed on as is. Returns the link of the local proxy.
+def start_socks_bridge(proxy_uri)
+    server = TCPServer.new("127.0.0.1", 0)
+
+    Thread.new do
+        loop do
+            Thread.new(server.accept) do |client|
+                upstream = nil
+                begin
+                    request_method, target, http_version = client.gets.to_s.split(" ")
+                    headers = ""
+                    while (line = client.gets) != nil && line != "\r\n"
+                        headers += line unless line.match?(/\AProxy-/i)
+                    end
+
+                    if request_method == "CONNECT"
+                        host, _, port = target.to_s.rpartition(":")
+                        upstream = socks5_connect(proxy_uri, host.delete("[]"), port.to_i)
+                        client.write("HTTP/1.1 200 Connection established\r\n\r\n")
+                    else
+                        target_uri = URI(target.to_s)
+                        upstream = socks5_connect(proxy_uri, target_uri.host, target_uri.port)
+                        upstream.write("#{request_method} #{target_uri.request_uri} #{http_version}\r\n" + headers + "\r\n")
+                    end
+                rescue => e
+                    puts "⚠️Could not connect through the proxy: #{e.message}."
+                    client.write("HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n") rescue nil
+                    client.close rescue nil
+                    upstream.close if upstream != nil
+                    next
+                end
+
+                # Pass everything along both ways until either side is done.
+                Thread.new do
+                    IO.copy_stream(client, upstream) rescue nil
+                    upstream.close_write rescue nil
+                end
+                IO.copy_stream(upstream, client) rescue nil
+                client.close rescue nil
+                upstream.close rescue nil
+            end
+        end
+    end
+
+    "http://127.0.0.1:#{server.addr[1]}"
+end
+
+# Every request (to Reddit, media websites, and the rest) goes through the proxy setting, if set. Both open-uri and Net::HTTP
+# read it from the standard http_proxy and https_proxy environment variables (along with no_proxy), which also work on their own.
+# SOCKS5 proxies (socks5:// or socks5h://) are used through a local HTTP proxy (see start_socks_bridge()). Other proxies stop
+# the script rather than letting requests bypass the proxy.
+proxy = settings['proxy'].to_s
+if proxy.match?(/\Ahttps?:\/\/\S+\z/i)
+    ENV['http_proxy'] = proxy
+    ENV['https_proxy'] = proxy
+elsif proxy.match?(/\Asocks5h?:\/\/\S+\z/i) && !options[:validate_config]
+    socks_bridge = start_socks_bridge(URI(proxy))
+    ENV['http_proxy'] = socks_bridge
+    ENV['https_proxy'] = socks_bridge
+elsif proxy != "" && !options[:validate_config]
+    puts "❌Error: The proxy #{proxy} is not supported. Use an http://, https://, or socks5:// proxy (e.g. Tor's SocksPort). Exiting..."
+    exit
+end
+
 version = settings['version']
+# A hash of the settings in effect (after the profile and the command line overrides), identifying them in source blocks.
+# The credentials under "auth" are left out, as they don't change how posts are saved.
+settings_digest = Digest::SHA256.hexdigest(JSON.generate(settings.reject { |key, _| key == 'auth' }))[0, 12]
 update_check_on_startup = settings['update_check_on_startup']
-show_auto_mod_comment = settings['show_auto_mod_comment']
-line_break_enabled = settings['line_break_between_parent_replies']
 show_upvotes_enabled = settings['show_upvotes']
-reply_depth_color_indicators_enabled = settings['reply_depth_color_indicators']
 overwrite_existing_file_enabled = settings['overwrite_existing_file']
 save_posts_by_subreddits = settings['save_posts_by_subreddits']
 show_timestamp = settings['show_timestamp']
+summary_and_full_files_enabled = settings['summary_and_full_files']
+show_subreddit_icon = settings['show_subreddit_icon']
+show_subreddit_banner = settings['show_subreddit_banner']
+hoist_op_comment_enabled = settings['hoist_op_comment']
+file_extension = settings['file_format'] == "txt" ? ".txt" : ".md"
+write_run_manifest_enabled = settings['write_run_manifest']
 
-# Only apply to replies and not actual post body.
-# When applied, reply body will be replaced by user-defined filtered_message.
-filtered_message = settings["filtered_message"]
-filtered_keywords = settings['filters']['keywords']
-filtered_min_upvotes = settings['filters']['min_upvotes']
-filtered_authors = settings['filters']['authors']
-filtered_regexes = settings['filters']['regexes']
+# Without a sort, Reddit uses the default sort of the subreddit (usually "best").
+comment_sort = options[:sort] || settings['comment_sort']
+comment_sort = "confidence" if comment_sort == "best"
+if comment_sort == ""
+    comment_sort = nil
+end
+if comment_sort != nil && !COMMENT_SORTS.include?(comment_sort)
+    puts "⚠️Unknown comment sort \"#{comment_sort}\". Supported sorts are #{COMMENT_SORTS.join(", ")}. Using Reddit's default sort..."
+    comment_sort = nil
+end
+show_embed_thumbnails_enabled = settings['show_embed_thumbnails']
+download_media_enabled = settings['download_media']
+gallery_index_enabled = settings['gallery_index']
+selftext_style = settings['selftext_style']
 
 directory = settings["default_save_location"]
 
+# Steps that the text of posts and replies can go through before being formatted (see process_text()), by name.
+# Code is never processed. Decoding HTML entities and linking u/ mentions are left to the formatting itself.
+TEXT_PROCESSORS = {
+  'clean_links' => lambda { |text, settings| clean_links_in(text, settings) },
+  'spoilers' => lambda { |text, _| text.gsub(/(?:>|&gt;)!(.+?)!(?:<|&lt;)/m) { "[spoiler: #{$1}]" } },
+  'replacements' => lambda { |text, settings| apply_text_replacements(text, settings) }
+}
+
+# The steps of process_text() when text_processors isn't set.
+DEFAULT_TEXT_PROCESSORS = %w[clean_links spoilers replacements]
+
+# Keys of the settings, along with the keys of the settings that are objects with fixed keys. See validate_settings().
+KNOWN_SETTINGS = %w[
+  version update_check_on_startup show_upvotes reply_depth_color_indicators line_break_between_parent_replies
+  show_auto_mod_comment deleted_comments always_keep_distinguished overwrite_existing_file resync_existing_posts
+  save_posts_by_subreddits show_timestamp show_awards show_edited comment_inline_fields show_source_block locale
+  summary_and_full_files archive_bundle selftext_style heading_base_level comments_as_headings show_subreddit_icon
+  download_media media_max_bytes media_allowed_types media_store_location media_store_hard_links ffmpeg_path
+  imgur_client_id gallery_index hoist_op_comment show_embed_thumbnails media_position comment_sort clean_links
+  blocked_domains blocked_domain_action reply_depth_max max_comments max_comments_per_depth skip_if_fewer_comments_than
+  subreddit_listing subreddit_post_limit collections schedules file_format link_style txt_line_width newline_style
+  utf8_bom write_run_manifest write_feed feed_max_items anki_export anki_answers site_export_location site_generator
+  webhooks discord_webhook_url filtered_message filters templates default_save_location auth profiles limits proxy
+  retention archived_post_links max_file_name_length directory_template recover_deleted_parents text_processors
+  text_replacements show_subreddit_banner
+]
+KNOWN_NESTED_SETTINGS = {
+  'filters' => %w[
+    keywords min_upvotes min_upvotes_by_depth noise_filter_level authors regexes title_include title_exclude
+    post_authors_allow post_authors_deny
+  ],
+  'templates' => %w[post_header post_body comment_header comment_body],
+  'auth' => %w[login_on_startup client_id client_secret username password two_factor],
+  'limits' => %w[reddit_requests_per_minute media_downloads_per_minute_per_host],
+  'retention' => %w[max_age_days keep_top_per_subreddit max_megabytes_per_subreddit]
+}
+
+# Paths (in settings, in environment variables, and at the prompt) may start with "~" for the home folder, and contain
+# environment variables, either Unix-style ($NAME or ${NAME}) or Windows-style (%NAME%). Unknown variables are left as is.
+def expand_path(path)
+    if path == nil || path == ""
+        return path
+    end
+
+    path = path.gsub(/\$\{(\w+)\}|\$(\w+)|%(\w+)%/) { |match| ENV[$1 || $2 || $3] || match }
+    path.start_with?("~") ? File.expand_path(path) : path
+end
+
+# The name(s) of the subreddit(s) in a link or name like "r/rust", "rust", or "https://www.reddit.com/r/rust/",
+# or nil if it isn't one. Several subreddits combined with "+" (e.g. "r/rust+programming") are listed by Reddit as one.
+def subreddit_names(text)
+    match = text.strip.match(/\A(?:https:\/\/(?:www\.|old\.)?reddit\.com)?\/?(?:r\/)?([\w+\/]+?)\/?\z/)
+    if match == nil
+        return nil
+    end
+
+    # Each subreddit may have its own "r/" prefix, e.g. "r/rust+r/programming".
+    names = match[1].split("+").map { |name| name.sub(/^r\//, "") }
+    if names.empty? || names.any? { |name| !name.match?(/\A\w+\z/) }
+        return nil
+    end
+
+    names.join("+")
+end
+
+# The path on Reddit of the posts listed for a subreddit or multireddit (a custom feed of subreddits), e.g. "r/rust" for
+# "rust", "me/m/programming" for "m/programming" (one of the logged in user's), or "user/spez/m/programming" for
+# "u/spez/m/programming" (anyone's public one). Returns nil if it is neither.
+def listing_feed_path(text)
+    sub = subreddit_names(text)
+    if sub != nil
+        return "r/#{sub}"
+    end
+
+    match = text.strip.match(/\A(?:(?:u|user)\/([\w-]+)\/)?m\/(\w+)\/?\z/)
+    if match == nil
+        return nil
+    end
+
+    match[1] ? "user/#{match[1]}/m/#{match[2]}" : "me/m/#{match[2]}"
+end
+
+# Check the settings for mistakes that would otherwise only show up (or go unnoticed) while saving posts.
+# Returns the errors (which stop the script from working as expected) and the warnings.
+def validate_settings(settings)
+    errors = []
+    warnings = []
+
+    (settings.keys - KNOWN_SETTINGS).each { |key| warnings << "Unknown setting \"#{key}\" (misspelled, or from another version of the script?) is ignored." }
+    (KNOWN_SETTINGS - settings.keys).each { |key| warnings << "Setting \"#{key}\" is missing. Its default is used, if it has one. Get a fresh copy of settings.json to see it." }
+
+    KNOWN_NESTED_SETTINGS.each do |key, nested_keys|
+        unless settings[key].is_a?(Hash)
+            errors << "Setting \"#{key}\" must be an object." if settings.key?(key)
+            next
+        end
+
+        (settings[key].keys - nested_keys).each { |nested_key| warnings << "Unknown setting \"#{key}\" -> \"#{nested_key}\" is ignored." }
+    end
+
+    # Unlike other settings, filters has no default.
+    if !settings.key?('filters')
+        errors << "Setting \"filters\" is required. Get a fresh copy of settings.json, or add \"filters\": {}."
+    end
+
+    # Regexes, in the top-level filters and in the filters of each collection.
+    filter_sets = { "filters" => settings['filters'] }
+    (settings['collections'].is_a?(Hash) ? settings['collections'] : {}).each do |name, collection|
+        filter_sets["collections -> \"#{name}\" -> filters"] = collection['filters'] if collection.is_a?(Hash) && collection['filters'].is_a?(Hash)
+    end
+    filter_sets.each do |location, filters|
+        next unless filters.is_a?(Hash)
+
+        %w[regexes title_include title_exclude].each do |key|
+            Array(filters[key]).each do |pattern|
+                begin
+                    Regexp.new(pattern.to_s)
+                rescue RegexpError => e
+                    errors << "Invalid regex #{pattern.inspect} in #{location} -> \"#{key}\": #{e.message}."
+                end
+            end
+        end
+    end
+
+    Array(settings['text_replacements']).each do |rule|
+        if !rule.is_a?(Hash) || !rule.key?('pattern')
+            errors << "Each of text_replacements must be an object with a \"pattern\" and a \"replacement\", e.g. {\"pattern\": \"\\\\bu/spez\\\\b\", \"replacement\": \"u/[redacted]\"}."
+            next
+        end
+
+        begin
+            Regexp.new(rule['pattern'].to_s)
+        rescue RegexpError => e
+            errors << "Invalid regex #{rule['pattern'].inspect} in text_replacements: #{e.message}."
+        end
+    end
+
+    (Array(settings['text_processors']) - TEXT_PROCESSORS.keys).each { |name| warnings << "Unknown step \"#{name}\" in text_processors is skipped. Steps are #{TEXT_PROCESSORS.keys.join(", ")}." }
+
+    # The save location, unless it is prompted for.
+    save_location = settings['default_save_location']
+    if save_location == "DEFAULT_REDDIT_SAVE_LOCATION"
+        save_location = ENV["DEFAULT_REDDIT_SAVE_LOCATION"]
+        if save_location == nil || save_location == ""
+            errors << "default_save_location is \"DEFAULT_REDDIT_SAVE_LOCATION\", but that environment variable is not set. Set it, or set default_save_location to a path."
+        end
+    end
+    if save_location != nil && save_location != ""
+        save_location = expand_path(save_location)
+        if !File.directory?(save_location)
+            errors << "The save location #{save_location} is not a folder. Create it, or change default_save_location."
+        elsif !File.writable?(save_location)
+            errors << "The save location #{save_location} is not writable. Check its permissions, or change default_save_location."
+        end
+    end
+
+    # Collections (groups of subreddits for --collections) and schedules.
+    if settings.key?('collections') && !settings['collections'].is_a?(Hash)
+        errors << "Setting \"collections\" must be an object of collection names to collections."
+    elsif settings['collections'].is_a?(Hash)
+        settings['collections'].each do |name, collection|
+            if !collection.is_a?(Hash)
+                errors << "Collection \"#{name}\" must be an object, e.g. {\"subreddits\": [\"rust\", \"programming\"]}."
+                next
+            end
+
+            subreddits = collection['subreddits']
+            if !subreddits.is_a?(Array) || subreddits.empty? || !subreddits.all? { |sub| sub.is_a?(String) && sub.strip != "" }
+                errors << "Collection \"#{name}\" must have a non-empty \"subreddits\" list of subreddit names, e.g. [\"rust\", \"programming\"]."
+            end
+            if collection['listing'] != nil && !%w[hot new top rising].include?(collection['listing'])
+                errors << "Collection \"#{name}\" has an unknown listing \"#{collection['listing']}\". Use hot, new, top, or rising."
+            end
+            if collection['limit'] != nil && !collection['limit'].is_a?(Integer)
+                errors << "Collection \"#{name}\" must have a whole number as its \"limit\"."
+            end
+            (collection.keys - %w[subreddits listing limit filters]).each { |key| warnings << "Unknown key \"#{key}\" in collection \"#{name}\" is ignored." }
+        end
+    end
+
+    if settings['profiles'].is_a?(Hash)
+        settings['profiles'].each do |name, profile|
+            if !profile.is_a?(Hash)
+                errors << "Profile \"#{name}\" must be an object of settings, e.g. {\"file_format\": \"txt\"}."
+                next
+            end
+
+            (profile.keys - KNOWN_SETTINGS).each { |key| warnings << "Unknown setting \"#{key}\" in profile \"#{name}\" is ignored." }
+        end
+    end
+
+    if settings['schedules'].is_a?(Hash)
+        settings['schedules'].each do |sub, interval|
+            if listing_feed_path(sub) == nil
+                errors << "Schedule \"#{sub}\" is not a subreddit or multireddit. Use e.g. \"r/rust\", \"m/programming\", or \"u/spez/m/programming\"."
+            end
+            unless %w[hourly daily weekly].include?(interval.to_s) || interval.to_s.match?(/\A[1-9]\d*\z/)
+                errors << "Schedule of #{sub} has an unknown interval \"#{interval}\". Use hourly, daily, weekly, or a number of minutes."
+            end
+        end
+    end
+
+    if settings['proxy'].to_s != "" && !settings['proxy'].to_s.match?(/\A(https?|socks5h?):\/\/\S+\z/i)
+        errors << "Setting \"proxy\" must be an http://, https://, or socks5:// link, e.g. \"http://127.0.0.1:8118\" or \"socks5://127.0.0.1:9050\"."
+    end
+
+    [errors, warnings]
+end
+
+if options[:validate_config]
+    errors, warnings = validate_settings(settings)
+
+    puts "Checked #{File.expand_path(settings_path)}.\n\n"
+    warnings.each { |warning| puts "⚠️#{warning}" }
+    errors.each { |error| puts "❌Error: #{error}" }
+
+    if errors.empty?
+        puts "\n✅No errors found#{warnings.empty? ? "" : " (#{warnings.length} warnings)"}."
+        exit
+    end
+
+    puts "\n#{errors.length} errors found."
+    exit 1
+end
+
 # Note: This is an approximate count only and should not be used for anything critical.
 # It does not include replies that Reddit hides by default on the web experience.
 # The more replies a post has, the more replies get hidden by default, and the more inaccurate this count will be.
 replies_count = {}
 
-if update_check_on_startup == true
+# Names of the checksum manifest that releases may publish, listing the SHA-256 of each of their files ("<checksum>  <file name>").
+RELEASE_CHECKSUMS_FILE_NAMES = %w[SHA256SUMS SHA256SUMS.txt checksums.txt]
+
+# What a GitHub release offers: "source_only" when it is only a tag (with GitHub's source archives), "unverified" when the
+# script is published without a checksum manifest, and "verified" or "mismatch" depending on whether the published script
+# matches its checksum in the manifest. This is what a self-update would rely on before replacing the script.
+def release_download_status(release)
+    assets = release['assets'] || []
+    script_asset = assets.find { |asset| asset['name'] == File.basename(__FILE__) }
+    if script_asset == nil
+        return "source_only"
+    end
+
+    checksums_asset = assets.find { |asset| RELEASE_CHECKSUMS_FILE_NAMES.include?(asset['name']) }
+    if checksums_asset == nil
+        return "unverified"
+    end
+
+    checksums = URI.open(checksums_asset['browser_download_url']).read.lines.map(&:strip).reject(&:empty?).map { |line| line.split(/\s+\*?/, 2).reverse }.to_h
+    script = URI.open(script_asset['browser_download_url']).read
+    checksums[script_asset['name']].to_s.downcase == Digest::SHA256.hexdigest(script) ? "verified" : "mismatch"
+end
+
+if update_check_on_startup == true && REPLAY_DIRECTORY == nil && network_needed
     begin
         commits = JSON.parse(URI.open("https://api.github.com/repos/chauduyphanvu/reddit-markdown/releases").read)
 
@@ -61,6 +677,17 @@ if update_check_on_startup == true
         if latest_version.match?(/\d+\.\d+\.\d+/)
             if Gem::Version.new(latest_version) > Gem::Version.new(version)
                 puts "\nSuggestion: A new version (#{latest_version}) is available. Your current version is #{version}. You can download the latest version from https://github.com/chauduyphanvu/reddit-markdown."
+
+                case release_download_status(commits.first)
+                when "verified"
+                    puts "ℹ️The script published with #{latest_version} matches its checksum (SHA-256)."
+                when "mismatch"
+                    puts "⚠️The script published with #{latest_version} does not match its checksum. Do not use it, and get the source code of the release instead."
+                when "unverified"
+                    puts "ℹ️The script published with #{latest_version} has no checksums to verify it against."
+                else
+                    puts "ℹ️#{latest_version} is only published as source code (#{commits.first['html_url']})."
+                end
             end
         else
             puts "\nWarning: Found invalid version number in latest GitHub commit. Please check for updates manually at https://github.com/chauduyphanvu/reddit-markdown."
@@ -70,33 +697,104 @@ if update_check_on_startup == true
     end
 end
 
+# A non-empty user agent is required so that we aren't rate limited.
+USER_AGENT = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/51.0.2704.103 Safari/537.36"
+
+# Value of max_comments to save any number of replies.
+UNLIMITED_COMMENTS = -1
+
+# What makes a reply noise at each level of filters.noise_filter_level (0 disables the filter): at most max_score upvotes,
+# fewer than max_length characters, and at least min_depth deep. Each level catches more than the previous one.
+NOISE_FILTER_LEVELS = {
+  1 => { 'max_score' => 0, 'max_length' => 20, 'min_depth' => 3 },
+  2 => { 'max_score' => 1, 'max_length' => 50, 'min_depth' => 2 },
+  3 => { 'max_score' => 2, 'max_length' => 100, 'min_depth' => 1 }
+}
+
+# Markdown templates for the parts of a saved post. Each can be overridden under "templates" in settings.json.
+DEFAULT_TEMPLATES = {
+  'post_header' => "{{subreddit_banner}}{{subreddit_icon}}**{{subreddit}}** | Posted by u/{{author}} {{score_field}} {{awards_field}} {{timestamp_field}}\n\n{{heading}} {{title}}\n\nOriginal post: [{{url}}]({{url}})",
+  'post_body' => "{{selftext}}",
+  'comment_header' => "{{prefix}} {{color}} **{{author}}** {{badges}} {{score_field}} {{awards_field}} {{timestamp_field}} {{edited_field}} {{inline_fields}}",
+  'comment_body' => "{{body}}"
+}
+
+# Thousands separators of the locales supported by the locale setting.
+THOUSANDS_SEPARATORS = {
+  'en' => ",",
+  'de' => ".",
+  'es' => ".",
+  'it' => ".",
+  'nl' => ".",
+  'pt' => ".",
+  'fr' => " ",
+  'pl' => " ",
+  'ru' => " ",
+  'sv' => " ",
+  'de-CH' => "'"
+}
+
+# Value of reply_depth_max to save replies of any depth.
+UNLIMITED_DEPTH = -1
+
+# Domains of URL shorteners resolved when clean_links is enabled. redd.it links lead to Reddit posts.
+SHORTENER_DOMAINS = %w[bit.ly t.co tinyurl.com goo.gl ow.ly buff.ly is.gd rebrand.ly cutt.ly shorturl.at redd.it amzn.to]
+
+# Links resolved by clean_link() during this run.
+CLEANED_LINKS = {}
+
+# Leaves room for the subreddit folder and duplicate suffixes within the path length limits of most file systems.
+# Can be changed with the max_file_name_length setting.
+MAX_FILE_NAME_LENGTH = 100
+
+# File names that Windows reserves for devices, whatever their extension.
+WINDOWS_RESERVED_FILE_NAMES = %w[CON PRN AUX NUL] + (1..9).flat_map { |number| ["COM#{number}", "LPT#{number}"] }
+
+# Rough size of a saved post, with and without its media, used to warn about low free space before saving many posts.
+ESTIMATED_POST_BYTES = 200_000
+ESTIMATED_MEDIA_BYTES = 5_000_000
+
 # Supported colors to differentiate between replies of different depths.
-colors = %w[🟩 🟨 🟧 🟦 🟪 🟥 🟫 ⬛️ ⬜️]
+COLORS = %w[🟩 🟨 🟧 🟦 🟪 🟥 🟫 ⬛️ ⬜️]
 
 puts "\n"
 
 # Example of a "clean" Reddit link
 # This script also supports links that have other query parameters appended (that happens when you use the "Share" button to get the link)
 # https://www.reddit.com/r/pcmasterrace/comments/101kjyq/my_dad_has_been_playing_civilization_almost_daily/
-puts "✏️Enter the link to the Reddit post that you want to save. Separate multiple links with commas."
-puts "✏️Want a demo? Enter \"demo\"! Want a surprise? Enter \"surprise\"!"
-puts "✏️That's not enough? Enter \"snapshot\" to save what's on r/popular right now!"
-urls = gets.chomp
+# No need to prompt for links when the posts to save are already given on the command line, or come later (watch mode, local API).
+links_given = options[:users] || options[:subs] || options[:search] || options[:collections] || options[:saved] || options[:watch] || options[:serve] || options[:export_site] || options[:prune]
+if !links_given && !interactive
+    # One or more links per line, separated by commas or whitespace.
+    urls = $stdin.read.to_s.split(/[\s,]+/).reject(&:empty?).join(",")
 
-while urls == nil || urls == ""
-    puts "❌Error: No links provided. Try again."
-    urls = gets.chomp
+    if urls == ""
+        puts "❌Error: No links provided on standard input. Exiting..."
+        exit
+    end
+elsif !links_given
+    puts "✏️Enter the link to the Reddit post that you want to save. Separate multiple links with commas."
+    puts "✏️Want a demo? Enter \"demo\"! Want a surprise? Enter \"surprise\"!"
+    puts "✏️That's not enough? Enter \"snapshot\" to save what's on r/popular right now!"
+    puts "✏️You can also enter subreddits (e.g. r/rust, or r/rust+programming) to save the posts listed in them."
+    urls = $stdin.gets.chomp
+
+    while urls == nil || urls == ""
+        puts "❌Error: No links provided. Try again."
+        urls = $stdin.gets.chomp
+
+        puts "\n"
+    end
 
     puts "\n"
 end
 
-puts "\n"
-
 # To avoid having to enter the save location every time, you can set the DEFAULT_REDDIT_SAVE_LOCATION environment variable.
 # For it to take effect, the env var must be set once BEFORE running the script, and
 # the default_save_location value in settings.json must be set to "DEFAULT_REDDIT_SAVE_LOCATION".
+# Alternatively, default_save_location can be set to the path itself, e.g. "~/reddit".
 if directory == "DEFAULT_REDDIT_SAVE_LOCATION"
-    directory = ENV["DEFAULT_REDDIT_SAVE_LOCATION"]
+    directory = expand_path(ENV["DEFAULT_REDDIT_SAVE_LOCATION"])
 
     if directory == nil || directory == ""
         puts "❌Error: DEFAULT_REDDIT_SAVE_LOCATION environment variable not set. You must set it to a valid path before running the script.
@@ -104,10 +802,20 @@ if directory == "DEFAULT_REDDIT_SAVE_LOCATION"
         puts "Exiting..."
         exit
     end
+elsif directory != nil && directory != ""
+    directory = expand_path(directory)
+
+    unless File.directory?(directory)
+        puts "❌Error: The default_save_location in settings.json (#{directory}) is not a folder. Exiting..."
+        exit
+    end
+elsif !interactive
+    puts "❌Error: The save location can't be prompted for when not running in a terminal. Set the default_save_location value in settings.json. Exiting..."
+    exit
 else
     puts "=> Enter a full path to save the post(s) to. Hit Enter/Return for current directory, which is #{Dir.pwd}."
-    directory = gets.chomp
-    directory = directory.strip
+    directory = $stdin.gets.chomp
+    directory = expand_path(directory.strip)
 
     if directory == ""
         directory = Dir.pwd
@@ -115,64 +823,1679 @@ else
 
     until File.directory?(directory)
         puts "❌Error: Invalid path. Try again."
-        directory = gets.chomp
+        directory = expand_path($stdin.gets.chomp.strip)
 
         puts "\n"
     end
 end
 
+# Other path settings
+settings['media_store_location'] = expand_path(settings['media_store_location'])
+settings['ffmpeg_path'] = expand_path(settings['ffmpeg_path'])
+settings['site_export_location'] = expand_path(settings['site_export_location'])
+
 # By appending ".json" to the end of a Reddit post URL, we can get the JSON payload for the post.
 # This way we don't have to actually tap into the Reddit API. No authentication is required.
 #
 # Note that this payload does not necessarily include all the replies. See get_replies() for more info below.
 # A non-empty user agent is required so that we aren't rate limited (a sample one is provided below).
-def download_post_json(url)
-    URI.open(
-      url + ".json",
-      "User-Agent" => "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/51.0.2704.103 Safari/537.36",
-      :read_timeout => 5
-    ) {
-      |f| json = JSON.parse(f.read)
-        json
+# Extra query parameters (e.g. "?comment=abc123") can be passed via query.
+# With an access token (see get_access_token()), the request is made on behalf of the logged in user.
+def download_post_json(url, query = "", access_token = nil)
+    headers = { "User-Agent" => USER_AGENT }
+    request_url = url
+
+    if access_token != nil
+        # Authenticated requests must go to oauth.reddit.com instead of www.reddit.com.
+        request_url = url.sub("https://www.reddit.com", "https://oauth.reddit.com")
+        headers["Authorization"] = "bearer #{access_token}"
+    end
+
+    body = with_recording(url + ".json" + query) {
+        throttle("reddit.com", LIMITS['reddit_requests_per_minute'])
+        puts "🔍GET #{request_url}.json#{query}"
+        URI.open(
+          request_url + ".json" + query,
+          headers.merge(:read_timeout => 5)
+        ) { |f| f.read }
     }
-end
 
-# Get all the child replies to a parent (top-level) reply.
-def get_replies(reply)
-    child_replies = {}
+    JSON.parse(body)
+end
 
-    if reply['data']['replies'] != ""
-        reply['data']['replies']['data']['children'].each do |child_reply|
-            child_reply_id = child_reply['data']['id']
-            child_reply_depth = child_reply['data']['depth']
-            child_reply_body = child_reply['data']['body']
+# When each request was made in the last minute, by resource (e.g. "reddit.com", or the host of a media).
+REQUEST_TIMES = Hash.new { |times, resource| times[resource] = [] }
+REQUEST_TIMES_LOCK = Mutex.new
 
-            # On the web, Reddit hides a subset of replies that you'd have to manually click to see.
-            # Those replies typically have very low upvotes and are usually just spam.
-            # This script preserves that experience and skips replies that fall into that category.
-            if child_reply_body == nil || child_reply_body == ""
-                next
-            end
+# Wait, if needed, so that no more than max_per_minute requests are made to a resource in any minute (0 or nil for no limit).
+# Safe to call from several threads (e.g. the local API and watch mode), which then share the limit.
+def throttle(resource, max_per_minute)
+    if max_per_minute == nil || max_per_minute <= 0
+        return
+    end
 
-            child_replies[child_reply_id] = {
-              'depth' => child_reply_depth,
-              'child_reply' => child_reply
-            }
+    REQUEST_TIMES_LOCK.synchronize do
+        times = REQUEST_TIMES[resource]
+        times.reject! { |time| Process.clock_gettime(Process::CLOCK_MONOTONIC) - time >= 60 }
 
-            child_replies.merge!(get_replies(child_reply))
+        if times.length >= max_per_minute
+            wait = 60 - (Process.clock_gettime(Process::CLOCK_MONOTONIC) - times.first)
+            puts "ℹ️Waiting #{wait.ceil} seconds to stay within #{max_per_minute} requests per minute to #{resource}..."
+            sleep(wait)
+            times.shift
         end
-    end
 
-    child_replies
+        times << Process.clock_gettime(Process::CLOCK_MONOTONIC)
+    end
 end
 
-# Resolve the file name based on a number of rules.
-def resolve_full_path(url, directory, overwrite_existing_file_enabled, save_posts_by_subreddits, subreddit)
-    file_name = url.split("/").last
-    subreddit = subreddit.gsub("r/", "")
-    full_path = directory
+# Write the run report (with --report): the outcome of each link of the run, as JSON, or as CSV if the file ends with .csv.
+def write_run_report(report_path, run_report)
+    FileUtils.mkdir_p(File.dirname(report_path))
 
-    if save_posts_by_subreddits == true
+    if File.extname(report_path).downcase == ".csv"
+        columns = ['url', 'status', 'path', 'bytes_written', 'media_count', 'error']
+        rows = [columns] + run_report.map { |entry| columns.map { |column| entry[column] } }
+        csv = rows.map { |row| row.map { |field| '"' + field.to_s.gsub('"', '""') + '"' }.join(",") }.join("\n") + "\n"
+        File.open(report_path, "w") { |file| file.write(csv) }
+    else
+        File.open(report_path, "w") { |file| file.write(JSON.pretty_generate(run_report)) }
+    end
+end
+
+# Write a progress event (with --events): a JSON object on its own line, with the type of event, the time, and the given data.
+# Types of events: run_started, post_started, media_downloaded, post_finished, and run_finished.
+def emit_event(type, data = {})
+    if EVENTS_IO == nil
+        return
+    end
+
+    EVENTS_IO.puts(JSON.generate({ 'event' => type, 'time' => Time.now.iso8601 }.merge(data)))
+end
+
+# The file a response is recorded in (see with_recording()), named after the link it was downloaded from.
+def recording_path(directory, url)
+    "#{directory}/#{Digest::SHA256.hexdigest(url)[0, 16]}"
+end
+
+# Download something with the given block, unless replaying (--replay), in which case the recorded response is returned instead.
+# When recording (--record), the downloaded response is saved. Responses missing from a recording are treated as 404s.
+def with_recording(url)
+    if REPLAY_DIRECTORY != nil
+        path = recording_path(REPLAY_DIRECTORY, url)
+        unless File.exist?(path)
+            raise OpenURI::HTTPError.new("404 Not in recording (#{url})", nil)
+        end
+
+        return File.binread(path)
+    end
+
+    body = yield
+
+    if RECORD_DIRECTORY != nil
+        FileUtils.mkdir_p(RECORD_DIRECTORY)
+        File.binwrite(recording_path(RECORD_DIRECTORY, url), body)
+    end
+
+    body
+end
+
+# Download a page or API response other than Reddit's (e.g. the Imgur API) as text, going through with_recording() so that
+# replays (--replay) never go to the network, and within the limit on requests per website (see LIMITS).
+def fetch_url(url, headers = {})
+    with_recording(url) {
+        throttle(URI(url).host, LIMITS['media_downloads_per_minute_per_host'])
+        puts "🔍GET #{url}"
+        URI.open(url, { "User-Agent" => USER_AGENT, :read_timeout => 10 }.merge(headers)).read
+    }
+end
+
+# Log in to Reddit with the credentials in the "auth" settings and get a user-scoped access token.
+# This requires a "script" app, which can be created at https://www.reddit.com/prefs/apps.
+# For accounts with two-factor authentication enabled, the current code must be given as well.
+def get_access_token(auth, two_factor_code = nil)
+    password = auth['password']
+    if two_factor_code != nil && two_factor_code != ""
+        # Reddit expects the code to be appended to the password.
+        password = "#{password}:#{two_factor_code}"
+    end
+
+    uri = URI("https://www.reddit.com/api/v1/access_token")
+    request = Net::HTTP::Post.new(uri)
+    request.basic_auth(auth['client_id'], auth['client_secret'])
+    request["User-Agent"] = USER_AGENT
+    request.set_form_data("grant_type" => "password", "username" => auth['username'], "password" => password)
+
+    throttle("reddit.com", LIMITS['reddit_requests_per_minute'])
+    response = Net::HTTP.start(uri.hostname, uri.port, :use_ssl => true) { |http| http.request(request) }
+    unless response.is_a?(Net::HTTPSuccess)
+        raise "HTTP #{response.code} #{response.message}"
+    end
+
+    # Wrong credentials still return a 200, but with an error (e.g. "invalid_grant") in the payload.
+    json = JSON.parse(response.body)
+    if json['access_token'] == nil
+        raise json['error'] || "no access token returned"
+    end
+
+    json['access_token']
+end
+
+# Reddit's access tokens expire after an hour. Long runs (watch mode, the local API) log in again a bit before that.
+ACCESS_TOKEN_RENEWAL_SECONDS = 55 * 60
+
+# Whether everything needed to log in is set under "auth" in settings.json.
+def credentials_set?(auth)
+    %w[client_id client_secret username password].all? { |key| auth[key] != nil && auth[key] != "" }
+end
+
+# Log in to Reddit (see get_access_token()), asking for the two-factor authentication code if needed.
+# Returns the access token, or nil if logging in failed.
+def log_in(auth, interactive)
+    unless credentials_set?(auth)
+        puts "❌Error: Logging in requires client_id, client_secret, username, and password to be set under \"auth\" in settings.json."
+        return nil
+    end
+
+    two_factor_code = nil
+    if auth['two_factor'] == true && !interactive
+        puts "⚠️The two-factor authentication code can't be prompted for when not running in a terminal. Logging in without it..."
+    elsif auth['two_factor'] == true
+        puts "✏️Enter the current two-factor authentication code for u/#{auth['username']}."
+        two_factor_code = $stdin.gets.to_s.strip
+    end
+
+    begin
+        access_token = get_access_token(auth, two_factor_code)
+        puts "✅Logged in as u/#{auth['username']}.\n\n"
+        access_token
+    rescue => e
+        puts "❌Error: Could not log in to Reddit: #{e.message}."
+        nil
+    end
+end
+
+# Unsave a post or comment (identified by its full name, e.g. "t3_101kjyq") on behalf of the logged in user.
+def unsave(full_name, access_token)
+    uri = URI("https://oauth.reddit.com/api/unsave")
+    request = Net::HTTP::Post.new(uri)
+    request["Authorization"] = "bearer #{access_token}"
+    request["User-Agent"] = USER_AGENT
+    request.set_form_data("id" => full_name)
+
+    throttle("reddit.com", LIMITS['reddit_requests_per_minute'])
+    response = Net::HTTP.start(uri.hostname, uri.port, :use_ssl => true) { |http| http.request(request) }
+    response.is_a?(Net::HTTPSuccess)
+end
+
+# POST a JSON payload to a webhook. Webhooks that can't be reached are reported, but don't stop the post from being saved.
+def post_to_webhook(webhook_url, payload)
+    uri = URI(webhook_url)
+    request = Net::HTTP::Post.new(uri)
+    request["Content-Type"] = "application/json"
+    request["User-Agent"] = USER_AGENT
+    request.body = JSON.generate(payload)
+
+    response = Net::HTTP.start(uri.hostname, uri.port, :use_ssl => uri.scheme == "https", :open_timeout => 5, :read_timeout => 5) { |http| http.request(request) }
+    unless response.is_a?(Net::HTTPSuccess)
+        puts "⚠️Webhook #{webhook_url} answered with #{response.code}."
+    end
+rescue => e
+    puts "⚠️Could not notify webhook #{webhook_url}: #{e.message}."
+end
+
+# Notify that a post was saved (or failed to be): POST the given payload to each of the webhooks setting's endpoints,
+# e.g. to trigger an automation, and post an embed to the Discord channel of discord_webhook_url, if set.
+# Replayed runs (--replay) don't notify anyone, as they don't go to the network.
+def notify_webhooks(payload, settings)
+    if REPLAY_DIRECTORY != nil
+        return
+    end
+
+    (settings['webhooks'] || []).each do |webhook_url|
+        post_to_webhook(webhook_url, payload)
+    end
+
+    discord_webhook_url = settings['discord_webhook_url'].to_s
+    if discord_webhook_url == ""
+        return
+    end
+
+    fields = []
+    fields << { 'name' => "Subreddit", 'value' => payload['subreddit'], 'inline' => true } if payload['subreddit']
+    fields << { 'name' => "Score", 'value' => format_number(payload['score'], settings), 'inline' => true } if payload['score']
+    fields << { 'name' => "Saved at", 'value' => "`#{payload['path']}`" } if payload['path']
+    fields << { 'name' => "Error", 'value' => payload['error'] } if payload['error']
+
+    post_to_webhook(discord_webhook_url, {
+      'embeds' => [{
+        'title' => truncate(CGI.unescapeHTML((payload['title'] || payload['url']).to_s), 256),
+        'url' => payload['url'],
+        'description' => payload['status'] == "failed" ? "❌ Could not save this post." : "✅ Post #{payload['status']}.",
+        'color' => payload['status'] == "failed" ? 0xE74C3C : 0x2ECC71,
+        'fields' => fields
+      }]
+    })
+end
+
+# Get the links to the icon and the banner of a subreddit (e.g. "r/pics"), each nil if it doesn't have one.
+def get_subreddit_image_urls(subreddit, access_token = nil)
+    about = download_post_json("https://www.reddit.com/#{subreddit}/about", "", access_token)
+
+    # Subreddits using the redesign have a community icon and a banner background. Older ones may only have the legacy ones.
+    [%w[community_icon icon_img], %w[banner_background_image banner_img]].map { |keys|
+        image_url = keys.map { |key| about['data'][key] }.find { |url| url != nil && url != "" }
+
+        # Links in the payload are HTML-escaped, and the escaped version of signed links (with "&amp;") is rejected.
+        image_url ? CGI.unescapeHTML(image_url) : nil
+    }
+end
+
+# Name of the archive index, kept at the root of the save location.
+ARCHIVE_INDEX_FILE_NAME = "reddit_markdown_index.json"
+
+# Read the archive index of the given save location: saved posts by ID (or "<post ID>_<comment ID>" for comment threads),
+# each with its link, path, title, subreddit, upvotes, when it was saved, and the IDs of the replies it had then.
+def load_archive_index(directory)
+    path = "#{directory}/#{ARCHIVE_INDEX_FILE_NAME}"
+    File.exist?(path) ? JSON.parse(File.read(path)) : {}
+rescue JSON::ParserError
+    puts "⚠️The archive index at #{path} is not valid JSON. Starting a new one..."
+    {}
+end
+
+# Write the archive index of the given save location, replacing the previous one.
+def save_archive_index(directory, archive_index)
+    File.open("#{directory}/#{ARCHIVE_INDEX_FILE_NAME}", "w") { |file| file.write(JSON.pretty_generate(archive_index)) }
+end
+
+# Name of the RSS feed of recently saved posts, kept at the root of the save location.
+FEED_FILE_NAME = "feed.xml"
+
+# Write an RSS feed of the most recently saved posts of the archive index, for feed readers to follow what is being saved
+# (e.g. in watch mode). Each item links to the saved file, and to the post on Reddit.
+def write_archive_feed(directory, archive_index, max_items)
+    entries = archive_index.values.sort_by { |entry| entry['saved_at'].to_s }.reverse.first(max_items)
+
+    items = entries.map { |entry|
+        local_link = "file://" + URI::DEFAULT_PARSER.escape(File.expand_path(entry['path']))
+        <<~ITEM
+          <item>
+            <title>#{CGI.escapeHTML(entry['title'].to_s)}</title>
+            <link>#{CGI.escapeHTML(local_link)}</link>
+            <guid isPermaLink="false">#{CGI.escapeHTML(entry['url'].to_s)}</guid>
+            <description>#{CGI.escapeHTML("r/#{entry['subreddit']}: #{entry['url']}")}</description>
+            <source url="#{CGI.escapeHTML(entry['url'].to_s)}">Reddit</source>
+            <pubDate>#{Time.parse(entry['saved_at']).rfc2822}</pubDate>
+          </item>
+        ITEM
+    }
+
+    feed = <<~FEED
+      <?xml version="1.0" encoding="UTF-8"?>
+      <rss version="2.0">
+      <channel>
+        <title>Reddit Markdown archive</title>
+        <link>#{CGI.escapeHTML("file://" + URI::DEFAULT_PARSER.escape(File.expand_path(directory)))}</link>
+        <description>Posts recently saved by Reddit Markdown</description>
+        <lastBuildDate>#{Time.now.rfc2822}</lastBuildDate>
+      #{items.join}</channel>
+      </rss>
+    FEED
+
+    File.open("#{directory}/#{FEED_FILE_NAME}", "w") { |file| file.write(feed) }
+end
+
+# Name of the Anki deck of question posts (see anki_card()), kept at the root of the save location.
+ANKI_DECK_FILE_NAME = "reddit_markdown_anki.txt"
+
+# Whether a post asks a question (e.g. "Why do cats purr?" or "ELI5: How do vaccines work"), and can be turned into a flashcard.
+def question_post?(post_data)
+    title = post_data['title'].to_s.strip
+    title.end_with?("?") || title.match?(/\AELI5\b/i) || post_data['subreddit'].to_s.match?(/\A(explainlikeimfive|ask)/i)
+end
+
+# A field of an Anki card: HTML, on a single line, as tabs and line breaks separate fields and cards.
+def anki_field(markdown)
+    CGI.escapeHTML(markdown.to_s.strip).gsub("\t", " ").gsub(/\r?\n/, "<br>")
+end
+
+# A flashcard (a line of an Anki deck, see append_anki_card()) for a question post: the title and body of the post on the front,
+# and its top answers on the back (the top-level replies with the most upvotes, leaving out deleted, stickied, and AutoModerator ones).
+def anki_card(post_data, response, max_answers, url)
+    answers = response.select { |reply|
+        reply['kind'] == "t1" && !deleted_reply?(reply) && reply['data']['stickied'] != true &&
+          reply['data']['author'] != "AutoModerator" && reply['data']['body'].to_s != ""
+    }.sort_by { |reply| -reply['data']['ups'].to_i }.first(max_answers)
+
+    if answers.empty?
+        return nil
+    end
+
+    front = "<b>#{anki_field(post_data['title'])}</b>"
+    front += "<br><br>#{anki_field(post_data['selftext'])}" if post_data['selftext'].to_s.strip != ""
+
+    back = answers.map { |reply| "<b>u/#{anki_field(reply['data']['author'])}</b> (⬆️ #{reply['data']['ups'].to_i})<br>#{anki_field(reply['data']['body'])}" }.join("<hr>")
+    back += "<br><br><a href=\"#{CGI.escapeHTML(url)}\">r/#{anki_field(post_data['subreddit'])}</a>"
+
+    [front, back, "reddit r/#{post_data['subreddit']}"].join("\t")
+end
+
+# Add a card to the Anki deck of the given save location, a tab-separated text file that Anki imports (File > Import)
+# as Basic notes, with the subreddit as tags.
+def append_anki_card(directory, card)
+    path = "#{directory}/#{ANKI_DECK_FILE_NAME}"
+    header = File.exist?(path) ? "" : "#separator:tab\n#html:true\n#tags column:3\n"
+    File.open(path, "a") { |file| file.write(header + card + "\n") }
+end
+
+# Front matter for the static site export (see export_site()), in TOML, which both Hugo and Zola read. Taxonomies (e.g. the
+# subreddit) and other fields are where the site_generator setting expects them: Zola only allows its own keys at the top
+# level, with taxonomies under [taxonomies] and everything else under [extra], while Hugo takes taxonomies at the top level
+# and everything else under [params].
+def site_front_matter(fields, settings, extra = {}, taxonomies = {})
+    lines = fields.map { |key, value| "#{key} = #{value.is_a?(String) ? JSON.generate(value) : value}" }
+    taxonomy_lines = taxonomies.map { |name, terms| "#{name} = #{JSON.generate(terms)}" }
+
+    if settings['site_generator'] == "hugo"
+        lines += taxonomy_lines
+    elsif !taxonomies.empty?
+        lines << "" << "[taxonomies]"
+        lines += taxonomy_lines
+    end
+
+    unless extra.empty?
+        lines << "" << (settings['site_generator'] == "hugo" ? "[params]" : "[extra]")
+        lines += extra.map { |key, value| "#{key} = #{JSON.generate(value)}" }
+    end
+
+    "+++\n#{lines.join("\n")}\n+++\n\n"
+end
+
+# The section (folder under content/) of a saved post in the static site export: its subreddit.
+def site_section(entry)
+    entry['subreddit'].to_s == "" ? "reddit" : entry['subreddit'].to_s.gsub(/[^\w-]/, "_")
+end
+
+# Write the index pages of the static site export: content/_index.md, and content/<section>/_index.md for the given section.
+# Existing index pages are left as is, so that they can be customized.
+def write_site_indexes(content_directory, section, subreddit, settings)
+    FileUtils.mkdir_p("#{content_directory}/#{section}")
+
+    unless File.exist?("#{content_directory}/_index.md")
+        File.write("#{content_directory}/_index.md", site_front_matter({ 'title' => "Reddit archive", 'sort_by' => "date" }, settings))
+    end
+
+    unless File.exist?("#{content_directory}/#{section}/_index.md")
+        title = subreddit.to_s == "" ? "Reddit" : "r/#{subreddit}"
+        File.write("#{content_directory}/#{section}/_index.md", site_front_matter({ 'title' => title, 'sort_by' => "date" }, settings))
+    end
+end
+
+# The file names of the media that a saved file links to in the "media" folder next to it (e.g. abc123_1.jpg for media/abc123_1.jpg).
+def media_file_names_in(text)
+    text.scan(/(?<![\w\/])media\/([^\s)"'\]>]+)/).flatten.uniq
+end
+
+# The saved file of a post in the archive index (its full file with summary_and_full_files) and its media folder.
+def saved_post_file_and_media_directory(entry, settings)
+    file_extension = settings['file_format'] == "txt" ? ".txt" : ".md"
+    if File.directory?(entry['path'])
+        ["#{entry['path']}/full#{file_extension}", "#{entry['path']}/media"]
+    else
+        [entry['path'], "#{File.dirname(entry['path'])}/media"]
+    end
+end
+
+# Export a saved post (an entry of the archive index) as a page bundle of the static site export
+# (content/<subreddit>/<post ID>/index.md), along with the media it uses, so that relative links to them keep working.
+# Returns whether the post was exported.
+def export_site_page(content_directory, key, entry, settings)
+    saved_file, media_directory = saved_post_file_and_media_directory(entry, settings)
+    unless File.file?(saved_file)
+        puts "⚠️#{saved_file} no longer exists. Skipping..."
+        return false
+    end
+
+    section = site_section(entry)
+    write_site_indexes(content_directory, section, entry['subreddit'], settings)
+    page_directory = "#{content_directory}/#{section}/#{key.gsub(/[^\w-]/, "_")}"
+    FileUtils.mkdir_p(page_directory)
+
+    body = File.read(saved_file).delete_prefix("\uFEFF").gsub("\r\n", "\n")
+    front_matter = site_front_matter(
+      { 'title' => entry['title'].to_s, 'date' => entry['saved_at'] }.compact,
+      settings,
+      { 'original_url' => entry['url'].to_s },
+      entry['subreddit'].to_s == "" ? {} : { 'subreddits' => [entry['subreddit'].to_s] }
+    )
+    File.write("#{page_directory}/index.md", front_matter + body)
+
+    media_file_names_in(body).select { |file_name| File.file?("#{media_directory}/#{file_name}") }.each do |file_name|
+        FileUtils.mkdir_p("#{page_directory}/media")
+        FileUtils.cp("#{media_directory}/#{file_name}", "#{page_directory}/media/#{file_name}")
+    end
+
+    true
+end
+
+# Export all the saved posts of the archive index into a content tree for Hugo or Zola: content/_index.md, then a section
+# per subreddit (content/<subreddit>/_index.md) with a page bundle per post (see export_site_page()).
+# Returns the number of posts exported.
+def export_site(site_directory, archive_index, settings)
+    content_directory = "#{site_directory}/content"
+    archive_index.count { |key, entry| export_site_page(content_directory, key, entry, settings) }
+end
+
+# The files of a post in the archive index: its folder (with summary_and_full_files), or its file along with the ones saved
+# next to it (extracts, gallery index, and archive bundle).
+def saved_post_files(entry, settings)
+    if File.directory?(entry['path'])
+        return [entry['path']]
+    end
+
+    file_extension = settings['file_format'] == "txt" ? ".txt" : ".md"
+    base_path = entry['path'].chomp(file_extension)
+    [entry['path'], "#{base_path}_extracts#{file_extension}", "#{base_path}_gallery.json", "#{base_path}.tar.gz"].select { |path| File.exist?(path) }
+end
+
+# The media files that the saved file of a post in the archive index links to (e.g. media/abc123_1.jpg).
+def saved_post_media(entry, settings)
+    saved_file, media_directory = saved_post_file_and_media_directory(entry, settings)
+    unless File.file?(saved_file)
+        return []
+    end
+
+    media_file_names_in(File.read(saved_file)).map { |file_name| "#{media_directory}/#{file_name}" }.select { |path| File.file?(path) }
+end
+
+# Disk space used by a post in the archive index, including its media.
+def saved_post_bytes(entry, settings)
+    paths = saved_post_files(entry, settings).flat_map { |path| File.directory?(path) ? Dir.glob("#{path}/**/*") : [path] }
+    (paths + saved_post_media(entry, settings)).uniq.select { |path| File.file?(path) }.map { |path| File.size(path) }.sum
+end
+
+# Delete the saved posts that the retention settings no longer keep, along with their entries in the archive index:
+#   max_age_days: the posts saved more than the given number of days ago.
+#   keep_top_per_subreddit: all but the given number of posts with the most upvotes (when saved) of each subreddit.
+#   max_megabytes_per_subreddit: the oldest posts of each subreddit, until the rest fits in the given size.
+# Each rule is disabled with 0. Media are kept when another saved post still links to them (e.g. subreddit icons).
+# Returns the number of posts deleted and the bytes freed.
+def prune_archive(archive_index, retention, settings)
+    pruned_keys = []
+
+    max_age_days = retention['max_age_days'].to_i
+    if max_age_days > 0
+        oldest_kept = Time.now - max_age_days * 86_400
+        pruned_keys += archive_index.select { |_, entry| entry['saved_at'] != nil && Time.parse(entry['saved_at']) < oldest_kept }.keys
+    end
+
+    archive_index.reject { |key, _| pruned_keys.include?(key) }.group_by { |_, entry| entry['subreddit'].to_s }.each do |_, subreddit_entries|
+        keep_top = retention['keep_top_per_subreddit'].to_i
+        if keep_top > 0
+            by_score = subreddit_entries.sort_by { |_, entry| -entry['score'].to_i }
+            pruned_keys += by_score.drop(keep_top).map(&:first)
+            subreddit_entries = by_score.take(keep_top)
+        end
+
+        max_bytes = retention['max_megabytes_per_subreddit'].to_f * 1_000_000
+        if max_bytes > 0
+            used_bytes = 0
+            subreddit_entries.sort_by { |_, entry| entry['saved_at'].to_s }.reverse.each do |key, entry|
+                used_bytes += saved_post_bytes(entry, settings)
+                pruned_keys << key if used_bytes > max_bytes
+            end
+        end
+    end
+
+    pruned_keys.uniq!
+    kept_media = archive_index.reject { |key, _| pruned_keys.include?(key) }.values.flat_map { |entry| saved_post_media(entry, settings) }
+    freed_bytes = 0
+
+    pruned_keys.each do |key|
+        entry = archive_index[key]
+        freed_bytes += saved_post_bytes(entry, settings)
+
+        (saved_post_media(entry, settings) - kept_media).each { |media_path| FileUtils.rm_f(media_path) }
+        saved_post_files(entry, settings).each { |path| FileUtils.rm_rf(path) }
+        archive_index.delete(key)
+        puts "🗑️Deleted #{entry['path']}."
+    end
+
+    [pruned_keys.length, freed_bytes]
+end
+
+# Where deleted replies are looked up by ID when recover_deleted_parents is enabled (Arctic Shift, which archives Reddit).
+COMMENT_ARCHIVE_URL = "https://arctic-shift.photon-reddit.com/api/comments/ids"
+
+# Replies deleted on Reddit only keep "[deleted]" or "[removed]", which leaves their child replies answering nothing.
+# Look the deleted replies that have child replies up in the comment archive, and put back their text (and author)
+# where found, marking them as recovered. Returns the number of replies recovered.
+def recover_deleted_parents(response)
+    deleted_parents = all_replies(response).select { |reply|
+        deleted_reply?(reply) && reply['data']['replies'] != nil && reply['data']['replies'] != ""
+    }
+    if deleted_parents.empty?
+        return 0
+    end
+
+    recovered_count = 0
+    deleted_parents.each_slice(100) do |batch|
+        ids = batch.map { |reply| reply['data']['id'] }.join(",")
+        archived = JSON.parse(fetch_url("#{COMMENT_ARCHIVE_URL}?ids=#{ids}"))['data'] || []
+        archived_by_id = archived.map { |comment| [comment['id'], comment] }.to_h
+
+        batch.each do |reply|
+            comment = archived_by_id[reply['data']['id']]
+            if comment == nil || ["[deleted]", "[removed]", ""].include?(comment['body'].to_s)
+                next
+            end
+
+            reply['data']['body'] = comment['body']
+            reply['data']['author'] = comment['author'] if !["[deleted]", nil].include?(comment['author'])
+            reply['data']['recovered_from_archive'] = true
+            recovered_count += 1
+        end
+    end
+
+    recovered_count
+end
+
+# All the replies of a post (top-level replies and their child replies), depth-first.
+def all_replies(response)
+    response.select { |reply| reply['kind'] == "t1" }.flat_map { |reply|
+        child_replies = reply['data']['replies'] != nil && reply['data']['replies'] != "" ? get_replies(reply).values : []
+        [reply] + child_replies.map { |child_reply| child_reply['child_reply'] }
+    }
+end
+
+# Intervals of the schedules setting, in seconds. Intervals can also be given as a number of minutes.
+SCHEDULE_INTERVALS = {
+  'hourly' => 60 * 60,
+  'daily' => 24 * 60 * 60,
+  'weekly' => 7 * 24 * 60 * 60
+}
+
+# Name of the file keeping when each scheduled subreddit was last checked, kept at the root of the save location.
+SCHEDULE_STATE_FILE_NAME = "reddit_markdown_schedule.json"
+
+# Whether a scheduled subreddit is due to be checked, given when it last was (nil for never) and its interval.
+def schedule_due?(last_checked_at, interval)
+    seconds = SCHEDULE_INTERVALS[interval.to_s] || interval.to_i * 60
+    last_checked_at == nil || Time.now - Time.parse(last_checked_at) >= seconds
+end
+
+# Read when each scheduled subreddit was last checked, by subreddit.
+def load_schedule_state(directory)
+    path = "#{directory}/#{SCHEDULE_STATE_FILE_NAME}"
+    File.exist?(path) ? JSON.parse(File.read(path)) : {}
+rescue JSON::ParserError
+    {}
+end
+
+# Write when each scheduled subreddit was last checked, replacing the previous state.
+def save_schedule_state(directory, schedule_state)
+    File.open("#{directory}/#{SCHEDULE_STATE_FILE_NAME}", "w") { |file| file.write(JSON.pretty_generate(schedule_state)) }
+end
+
+# Name of the retry queue of watch mode: the posts that failed to be saved for a reason that may go away (e.g. being rate
+# limited, or the network being down), by post ID. Kept at the root of the save location, so that it survives restarts.
+RETRY_QUEUE_FILE_NAME = "reddit_markdown_retry_queue.json"
+
+# Posts in the retry queue are tried again after 5 minutes, then 10, 20, and so on up to a day, and given up on after
+# RETRY_MAX_ATTEMPTS failures.
+RETRY_BASE_DELAY_MINUTES = 5
+RETRY_MAX_DELAY_MINUTES = 24 * 60
+RETRY_MAX_ATTEMPTS = 8
+
+# Network errors that are usually over after a while, for which a post is put in the retry queue.
+TRANSIENT_NETWORK_ERRORS = [SocketError, Timeout::Error, Errno::ECONNRESET, Errno::ECONNREFUSED, Errno::EHOSTUNREACH, Errno::ENETUNREACH, OpenSSL::SSL::SSLError]
+
+# Whether a refused request (an OpenURI::HTTPError) is worth trying again later: when rate limited, or on Reddit's side errors.
+# Errors raised without a response (e.g. links missing from a --replay recording) are not.
+def retryable_http_error?(error)
+    if error.io == nil
+        return false
+    end
+
+    status = error.io.status[0].to_i
+    status == 429 || status >= 500
+end
+
+# Read the retry queue of watch mode (see RETRY_QUEUE_FILE_NAME): each post's link, failed attempts, last error, and when it is due.
+def load_retry_queue(directory)
+    path = "#{directory}/#{RETRY_QUEUE_FILE_NAME}"
+    File.exist?(path) ? JSON.parse(File.read(path)) : {}
+rescue JSON::ParserError
+    {}
+end
+
+# Write the retry queue of watch mode, replacing the previous one.
+def save_retry_queue(directory, retry_queue)
+    File.open("#{directory}/#{RETRY_QUEUE_FILE_NAME}", "w") { |file| file.write(JSON.pretty_generate(retry_queue)) }
+end
+
+# Seconds that a client of the local API has to send its whole request, so that one that never does can't hold up the others.
+API_READ_TIMEOUT = 10
+
+# Read a request to the local API (--serve): its method, path, query parameters, body, and headers (with lowercase names).
+# Raises Timeout::Error if the request takes longer than API_READ_TIMEOUT to arrive.
+def read_api_request(client)
+    method = target = body = nil
+    headers = {}
+
+    Timeout.timeout(API_READ_TIMEOUT) do
+        method, target = client.gets.to_s.split(" ")
+
+        while (line = client.gets) && line != "\r\n"
+            name, value = line.split(":", 2)
+            headers[name.downcase] = value.to_s.strip
+        end
+
+        body = headers['content-length'] ? client.read(headers['content-length'].to_i) : ""
+    end
+    uri = URI(target.to_s)
+
+    [method, uri.path, URI.decode_www_form(uri.query.to_s).to_h, body, headers]
+end
+
+# Answer a request to the local API with the given HTTP status (e.g. "200 OK") and JSON body.
+# Browser extensions (but not websites) are allowed to read the answer. This doesn't keep websites from sending requests,
+# which is what api_save_allowed?() is for.
+def write_api_response(client, status, body, origin = nil)
+    json = JSON.generate(body)
+    cors_headers = ""
+    if origin.to_s.match?(BROWSER_EXTENSION_ORIGIN)
+        cors_headers = "Access-Control-Allow-Origin: #{origin}\r\nAccess-Control-Allow-Methods: GET, POST\r\nAccess-Control-Allow-Headers: Content-Type\r\n"
+    end
+
+    client.write("HTTP/1.1 #{status}\r\nContent-Type: application/json\r\nContent-Length: #{json.bytesize}\r\n#{cors_headers}Connection: close\r\n\r\n#{json}")
+end
+
+# Origins of the pages of browser extensions, which are the only pages allowed to use the local API from a browser.
+BROWSER_EXTENSION_ORIGIN = /\A(chrome|moz|safari-web)-extension:\/\//
+
+# Whether a request to the local API may save posts: only a POST with a JSON body, which browsers never send from a
+# website without asking first (and the local API only says yes to browser extensions). With require_extension, it must
+# also come from a browser extension, rather than from any program.
+def api_save_allowed?(method, headers, require_extension)
+    content_type = headers['content-type'].to_s.split(";").first.to_s.strip.downcase
+    origin = headers['origin'].to_s
+
+    if method != "POST" || content_type != "application/json"
+        return false
+    end
+
+    origin.match?(BROWSER_EXTENSION_ORIGIN) || (!require_extension && origin == "")
+end
+
+# Length of the preview of a saved post in the answers of the local API's /save-current endpoint.
+API_PREVIEW_LENGTH = 2000
+
+# Links to Reddit posts and comments, e.g. https://www.reddit.com/r/rust/comments/abc123/title/ or .../abc123/title/def456/.
+REDDIT_POST_LINK = %r{https?://(?:www\.|old\.|new\.|np\.)?reddit\.com/r/\w+/comments/(\w+)(?:/[^/\s)\]>]*(?:/(\w+))?)?/?(?:\?[^\s)\]>]*)?}
+
+# The saved file of a post (or comment thread) linked to, if it is in the archive index (and isn't the one being saved).
+def archived_post_path(link, archive_index, current_key, settings)
+    match = link.match(REDDIT_POST_LINK)
+    key = match[2] ? "#{match[1]}_#{match[2]}" : match[1]
+    entry = archive_index[key]
+    if key == current_key || entry == nil
+        return nil
+    end
+
+    path = File.directory?(entry['path']) ? "#{entry['path']}/full#{settings['file_format'] == "txt" ? ".txt" : ".md"}" : entry['path']
+    File.exist?(path) ? path : nil
+end
+
+# Point the links to Reddit posts that are already saved to their saved files instead, per archived_post_links,
+# so that saved posts link to each other: by relative path, or as wiki-links (e.g. [[file name|text]]) for Obsidian and the like.
+def link_archived_posts(output, output_path, archive_index, current_key, settings)
+    style = settings['archived_post_links'].to_s
+    if style == ""
+        return output
+    end
+
+    from_directory = Pathname.new(File.expand_path(File.dirname(output_path)))
+    relative_path = lambda { |path| URI::DEFAULT_PARSER.escape(Pathname.new(File.expand_path(path)).relative_path_from(from_directory).to_s) }
+
+    if style == "wikilink" && settings['file_format'] != "txt"
+        output = output.gsub(/(?<!!)\[((?:\\.|[^\]\\])*)\]\((#{REDDIT_POST_LINK})\)/) { |markdown_link|
+            link_text = $1
+            link = $2
+            path = archived_post_path(link, archive_index, current_key, settings)
+            name = path && File.basename(path, ".*")
+            path == nil ? markdown_link : (link_text == link ? "[[#{name}]]" : "[[#{name}|#{link_text}]]")
+        }
+    end
+
+    output.gsub(REDDIT_POST_LINK) { |link|
+        path = archived_post_path(link, archive_index, current_key, settings)
+        if path == nil
+            link
+        elsif style == "wikilink" && settings['file_format'] != "txt"
+            "[[#{File.basename(path, ".*")}]]"
+        else
+            relative_path.call(path)
+        end
+    }
+end
+
+# Move a post saved in a workspace (see the main loop) to where it belongs. The saved file(s) are written to the workspace
+# first, then the media downloaded into the workspace's "media" folder are moved to media_directory (only the media that
+# weren't there yet are downloaded, see media_saved?()), and the saved file(s) are renamed to their paths last. If any of
+# it fails, what was moved is removed again and the files it replaced are put back, so that a post is either saved whole,
+# or not at all (only folders created on the way may be left behind, empty).
+def publish_workspace(workspace, outputs, media_directory)
+    staged_outputs = outputs.each_with_index.map { |(output_path, output), output_index|
+        workspace_path = "#{workspace}/#{output_index}_#{File.basename(output_path)}"
+        File.open(workspace_path, "w") { |file| file.write(output) }
+        [workspace_path, output_path]
+    }
+
+    published_paths = []
+    replaced_paths = {}
+    begin
+        Dir.glob("#{workspace}/media/*").each do |media_path|
+            FileUtils.mkdir_p(media_directory)
+            published_media_path = "#{media_directory}/#{File.basename(media_path)}"
+            FileUtils.mv(media_path, published_media_path)
+            published_paths << published_media_path
+        end
+
+        staged_outputs.each_with_index do |(workspace_path, output_path), output_index|
+            FileUtils.mkdir_p(File.dirname(output_path))
+
+            # Files saved before (e.g. with overwrite_existing_file) are kept aside until the post is saved whole.
+            if File.exist?(output_path)
+                previous_path = "#{workspace}/#{output_index}_previous"
+                FileUtils.mv(output_path, previous_path)
+                replaced_paths[output_path] = previous_path
+            end
+
+            FileUtils.mv(workspace_path, output_path)
+            published_paths << output_path
+        end
+    rescue
+        published_paths.each { |path| FileUtils.rm_f(path) }
+        replaced_paths.each { |output_path, previous_path| FileUtils.mv(previous_path, output_path) }
+        raise
+    end
+end
+
+# The replies of a post matching a pattern (with --extract), with their authors, scores, and links, e.g. to pull the
+# recipes, links, or codes out of a megathread. Returns nil when no reply matches.
+def render_extracts(response, pattern, title, settings)
+    matches = all_replies(response).select { |reply| !deleted_reply?(reply) && reply['data']['body'].to_s.match?(pattern) }
+    if matches.empty?
+        return nil
+    end
+
+    content = "#{"#" * heading_base_level(settings)} Replies matching `#{pattern.source}` in \"#{escape_markdown(CGI.unescapeHTML(title.to_s))}\"\n\n"
+    content += "_#{matches.length} replies._\n\n"
+
+    matches.each do |reply|
+        author = reply['data']['author']
+        permalink = "https://www.reddit.com#{reply['data']['permalink']}"
+        content += "* **u/#{escape_markdown(author)}** ⬆️ #{format_score(reply['data']['ups'].to_i, settings)} [Link](#{permalink})\n\n"
+        content += "\t#{format_comment_body(process_text(reply['data']['body'], settings), "\t")}\n\n"
+    end
+
+    content
+end
+
+# Package the saved file(s) of a post, along with the media they use, into a single .tar.gz at bundle_path.
+# The files keep the same layout as when saved (e.g. images under "media/"), so that the bundle can be extracted anywhere.
+def write_archive_bundle(bundle_path, outputs, media_directory)
+    media_file_names = outputs.values.flat_map { |output| media_file_names_in(output) }.uniq
+    media_file_names = media_file_names.select { |file_name| File.file?("#{media_directory}/#{file_name}") }
+
+    Zlib::GzipWriter.open(bundle_path) do |gzip|
+        Gem::Package::TarWriter.new(gzip) do |tar|
+            outputs.each do |output_path, output|
+                tar.add_file_simple(File.basename(output_path), 0644, output.bytesize) { |file| file.write(output) }
+            end
+
+            media_file_names.each do |file_name|
+                data = File.binread("#{media_directory}/#{file_name}")
+                tar.add_file_simple("media/#{file_name}", 0644, data.bytesize) { |file| file.write(data) }
+            end
+        end
+    end
+end
+
+# The free space (in bytes) of the drive the given folder is on, or nil if it can't be found out (e.g. on Windows, without df).
+def free_disk_space(directory)
+    output = IO.popen(["df", "-Pk", directory], err: File::NULL, &:read)
+    available_kb = output.lines.last.to_s.split[3]
+    available_kb&.match?(/\A\d+\z/) ? available_kb.to_i * 1024 : nil
+rescue SystemCallError
+    nil
+end
+
+# Download a file (e.g. an image) and return its content.
+# Files larger than media_max_bytes, or not of one of media_allowed_types, are not downloaded (an error is raised instead).
+def fetch_media(url, settings = {})
+    max_bytes = settings['media_max_bytes'] || 0
+    allowed_types = settings['media_allowed_types'] || []
+
+    # The size is checked as soon as it is known (from Content-Length), and while downloading for servers that don't send it.
+    check_size = lambda { |size|
+        if max_bytes > 0 && size != nil && size > max_bytes
+            raise "file is larger than media_max_bytes (#{max_bytes} bytes)"
+        end
+    }
+
+    with_recording(url) {
+        throttle(URI(url).host, LIMITS['media_downloads_per_minute_per_host'])
+        puts "🔍Downloading #{url}"
+        URI.open(url, "User-Agent" => USER_AGENT, :read_timeout => 10, :content_length_proc => check_size, :progress_proc => check_size) { |f|
+            if allowed_types.length > 0 && allowed_types.none? { |type| f.content_type.start_with?(type) }
+                raise "file type #{f.content_type} is not one of media_allowed_types"
+            end
+
+            f.read
+        }
+    }
+end
+
+# Download a file into the media store (media_store_location), where each file is named after the hash of its content so that
+# it is only stored once, however many posts use it. An index of links to stored files keeps the same link from being downloaded again.
+# The file is then hard-linked (if media_store_hard_links is enabled, and the file system allows it) or copied to the given path.
+def store_media(url, path, settings)
+    store = settings['media_store_location']
+    FileUtils.mkdir_p(store)
+
+    index_path = "#{store}/index.json"
+    index = File.exist?(index_path) ? JSON.parse(File.read(index_path)) : {}
+
+    stored_file_name = index[url]
+    if stored_file_name == nil || !File.exist?("#{store}/#{stored_file_name}")
+        data = fetch_media(url, settings)
+        stored_file_name = "#{Digest::SHA256.hexdigest(data)}#{File.extname(path)}"
+
+        unless File.exist?("#{store}/#{stored_file_name}")
+            File.binwrite("#{store}/#{stored_file_name}", data)
+        end
+
+        index[url] = stored_file_name
+        File.write(index_path, JSON.pretty_generate(index))
+    end
+
+    if settings['media_store_hard_links'] == true
+        begin
+            File.link("#{store}/#{stored_file_name}", path)
+            return
+        rescue SystemCallError
+            # e.g. the store and the save location are on different drives.
+        end
+    end
+
+    FileUtils.cp("#{store}/#{stored_file_name}", path)
+end
+
+# The media folder that the media of each workspace (see publish_workspace()) are moved to once the post is saved.
+PUBLISHED_MEDIA_DIRECTORIES = {}
+
+# Whether a file is already in the given media folder, or, for the media folder of a workspace, in the media folder
+# that it is published to.
+def media_saved?(media_directory, file_name)
+    published_media_directory = PUBLISHED_MEDIA_DIRECTORIES[media_directory]
+    File.exist?("#{media_directory}/#{file_name}") || (published_media_directory != nil && File.exist?("#{published_media_directory}/#{file_name}"))
+end
+
+# Download a file (e.g. an image) into the given folder under the given name, and return that name.
+# Files that were downloaded before (e.g. the icon of a subreddit shared by many posts) are not downloaded again.
+def download_media(url, media_directory, file_name, settings = {})
+    FileUtils.mkdir_p(media_directory)
+    path = "#{media_directory}/#{file_name}"
+
+    unless media_saved?(media_directory, file_name)
+        if settings['media_store_location'].to_s != ""
+            store_media(url, path, settings)
+        else
+            File.binwrite(path, fetch_media(url, settings))
+        end
+
+        emit_event("media_downloaded", { 'url' => url, 'path' => path })
+    end
+
+    file_name
+end
+
+# Whether ffmpeg can be run from the given path (or name, if it is in the PATH).
+def ffmpeg_available?(ffmpeg_path)
+    system(ffmpeg_path, "-version", out: File::NULL, err: File::NULL) == true
+end
+
+# Find the audio track of a video hosted on Reddit. Videos and their audio are served separately, and the
+# audio track is listed in the video's DASH playlist. Returns nil for videos without audio.
+def reddit_video_audio_url(reddit_video)
+    if reddit_video['has_audio'] == false || reddit_video['dash_url'] == nil
+        return nil
+    end
+
+    dash_url = CGI.unescapeHTML(reddit_video['dash_url'])
+    playlist = fetch_url(dash_url)
+
+    audio_file = playlist.scan(/<BaseURL>([^<]+)<\/BaseURL>/).flatten.find { |base_url| base_url.match?(/audio/i) }
+    audio_file ? URI.join(dash_url, audio_file).to_s : nil
+end
+
+# Download a video hosted on Reddit into the media folder, and return its file name.
+# The video is muxed with its audio track with ffmpeg (per ffmpeg_path) when possible. Otherwise, it is saved without audio.
+def download_reddit_video(reddit_video, media_directory, post_id, settings)
+    file_name = "#{post_id}.mp4"
+    if media_saved?(media_directory, file_name)
+        return file_name
+    end
+
+    video_file_name = download_media(CGI.unescapeHTML(reddit_video['fallback_url']), media_directory, "#{post_id}_video.mp4", settings)
+    video_path = "#{media_directory}/#{video_file_name}"
+
+    begin
+        audio_url = reddit_video_audio_url(reddit_video)
+    rescue => e
+        puts "⚠️Could not find the audio of the video: #{e.message}. Saving it without audio..."
+        audio_url = nil
+    end
+
+    ffmpeg_path = settings['ffmpeg_path'] || "ffmpeg"
+    if audio_url != nil && !ffmpeg_available?(ffmpeg_path)
+        puts "⚠️ffmpeg not found at \"#{ffmpeg_path}\". Saving the video without audio..."
+        audio_url = nil
+    end
+
+    if audio_url != nil
+        audio_path = "#{media_directory}/#{download_media(audio_url, media_directory, "#{post_id}_audio.mp4", settings)}"
+        muxed = system(ffmpeg_path, "-y", "-loglevel", "error", "-i", video_path, "-i", audio_path, "-c", "copy", "#{media_directory}/#{file_name}")
+        File.delete(audio_path)
+
+        if muxed == true
+            File.delete(video_path)
+            return file_name
+        end
+
+        puts "⚠️Could not add the audio to the video with ffmpeg. Saving it without audio..."
+    end
+
+    File.rename(video_path, "#{media_directory}/#{file_name}")
+    file_name
+end
+
+# Find the direct link(s) to the media of a post linking to another host: Imgur (images, GIFVs, and albums), Streamable, or RedGifs.
+# Returns a list of media, each with its direct link and file extension, which is empty for other links.
+# Imgur albums can only be looked up with the client ID of an Imgur app (imgur_client_id). Gfycat shut down in 2023.
+def resolve_external_media(url, settings)
+    uri = URI(url)
+    host = uri.host.to_s.downcase.sub(/^(www|m|i)\./, "")
+    path = uri.path.to_s
+
+    case host
+    when "imgur.com"
+        album_match = path.match(/^\/(?:a|gallery)\/(\w+)/)
+        if album_match
+            client_id = settings['imgur_client_id'].to_s
+            if client_id == ""
+                return []
+            end
+
+            album = JSON.parse(fetch_url("https://api.imgur.com/3/album/#{album_match[1]}/images", "Authorization" => "Client-ID #{client_id}"))
+            return album['data'].map { |image|
+                link = image['mp4'] || image['link']
+                { 'url' => link, 'extension' => File.extname(URI(link).path) }
+            }
+        end
+
+        image_match = path.match(/^\/(\w+)(\.\w+)?$/)
+        if image_match == nil
+            return []
+        end
+
+        # GIFVs are MP4 videos. Imgur serves images by their ID, whatever the extension.
+        if image_match[2] == ".gifv" || image_match[2] == ".mp4"
+            return [{ 'url' => "https://i.imgur.com/#{image_match[1]}.mp4", 'extension' => ".mp4" }]
+        end
+
+        extension = image_match[2] || ".jpg"
+        [{ 'url' => "https://i.imgur.com/#{image_match[1]}#{extension}", 'extension' => extension }]
+    when "streamable.com"
+        video = JSON.parse(fetch_url("https://api.streamable.com/videos/#{path.split("/").last}"))
+        link = video['files']['mp4']['url']
+        link = "https:#{link}" if link.start_with?("//")
+        [{ 'url' => link, 'extension' => ".mp4" }]
+    when "redgifs.com"
+        token = JSON.parse(fetch_url("https://api.redgifs.com/v2/auth/temporary"))['token']
+        gif = JSON.parse(fetch_url("https://api.redgifs.com/v2/gifs/#{path.split("/").last.downcase}", "Authorization" => "Bearer #{token}"))['gif']
+        [{ 'url' => gif['urls']['hd'] || gif['urls']['sd'], 'extension' => ".mp4" }]
+    else
+        []
+    end
+end
+
+# Download an image of a post into the media folder, and return the path to reference it by in the saved file.
+# If the download fails, the remote link is returned instead so that the image still shows up when online.
+def local_media_path(url, media_directory, file_name, settings = {})
+    "media/#{download_media(url, media_directory, file_name, settings)}"
+rescue => e
+    puts "⚠️Could not download #{url}: #{e.message}. Linking to it instead..."
+    url
+end
+
+# The path to reference a media in a reply by: the local copy if download_media is enabled (and the download works), or the link otherwise.
+def reply_media_path(url, media_directory, file_name, settings)
+    if settings['download_media'] != true
+        return url
+    end
+
+    local_media_path(url, media_directory, file_name, settings)
+end
+
+# Replies can have images and GIFs in them, listed in their media_metadata and referenced by ID (e.g. ![gif](giphy|abc123)),
+# or linked to on i.redd.it or preview.redd.it. Point the references to the images themselves, and render the links as images.
+def localize_comment_media(body, media_metadata, media_directory, settings)
+    body = body.gsub(/!\[([^\]]*)\]\(([^)\s]+)\)/) { |match|
+        alt_text = $1
+        media_id = $2
+        media = media_metadata[media_id]
+
+        url = media && media['s'] ? (media['s']['gif'] || media['s']['u']) : nil
+        if url == nil && media_id.start_with?("giphy|")
+            url = "https://i.giphy.com/media/#{media_id.split("|")[1]}/giphy.gif"
+        end
+
+        # Images already referenced by link
+        if url == nil
+            next match
+        end
+
+        url = CGI.unescapeHTML(url)
+        "![#{alt_text}](#{reply_media_path(url, media_directory, "#{media_id.gsub(/\W/, "_")}#{File.extname(URI(url).path)}", settings)})"
+    }
+
+    # Links to preview.redd.it are signed (by their query), and only work as is.
+    body.gsub(/(\]\()?(https:\/\/(?:preview|i)\.redd\.it\/(\w+)(\.\w+)(?:\?[^\s)\]]*)?)/) {
+        in_link = $1 != nil
+        media_id = $3
+        extension = $4
+        media = media_metadata[media_id]
+
+        url = media && media['s'] && media['s']['u'] ? media['s']['u'] : $2
+        path = reply_media_path(CGI.unescapeHTML(url), media_directory, "#{media_id}#{extension}", settings)
+        in_link ? "](#{path}" : "![image](#{path})"
+    }
+end
+
+# Run localize_comment_media() on the bodies of the given replies and all of their child replies, in place.
+def localize_reply_media(replies, media_directory, settings)
+    replies.each do |reply|
+        if reply['kind'] != "t1"
+            next
+        end
+
+        if reply['data']['body'] != nil
+            reply['data']['body'] = localize_comment_media(reply['data']['body'], reply['data']['media_metadata'] || {}, media_directory, settings)
+        end
+
+        child_replies = reply['data']['replies']
+        if child_replies != nil && child_replies != ""
+            localize_reply_media(child_replies['data']['children'], media_directory, settings)
+        end
+    end
+end
+
+# Get the images of a gallery post in order, with their caption and resolution.
+def get_gallery_images(post_data)
+    items = post_data['gallery_data'] ? post_data['gallery_data']['items'] : []
+    media_metadata = post_data['media_metadata'] || {}
+
+    items.map { |item|
+        media = media_metadata[item['media_id']]
+
+        # Images that failed processing on Reddit's side have no source.
+        if media == nil || media['status'] != "valid" || media['s'] == nil
+            next nil
+        end
+
+        # Animated images have a GIF instead of a still image. Links in the payload are HTML-escaped.
+        image_url = media['s']['u'] || media['s']['gif']
+        if image_url == nil
+            next nil
+        end
+
+        {
+          'url' => CGI.unescapeHTML(image_url),
+          'caption' => item['caption'].to_s,
+          'width' => media['s']['x'],
+          'height' => media['s']['y']
+        }
+    }.compact
+end
+
+# Get all the items (posts, and for some listings comments) in a listing, e.g. a user's submitted posts, following pagination.
+# Reddit returns at most 100 items per page, and stops paginating after about 1000 items. Only the first max_items are returned, if given.
+def get_listing(listing_url, access_token = nil, max_items = nil, params = "")
+    items = []
+    after = nil
+    page_size = max_items ? max_items.clamp(1, 100) : 100
+
+    loop do
+        json = download_post_json(listing_url, (after ? "?limit=#{page_size}&after=#{after}" : "?limit=#{page_size}") + params, access_token)
+
+        json['data']['children'].each do |item|
+            items << item['data']
+        end
+
+        after = json['data']['after']
+        break if after == nil || after == ""
+        break if max_items && items.length >= max_items
+    end
+
+    max_items ? items.first(max_items) : items
+end
+
+# Whether a post found in a listing should be saved, per filters.title_include and filters.title_exclude
+# (lists of case-insensitive regular expressions matched against the post title), and filters.post_authors_allow
+# and filters.post_authors_deny (exact usernames), and skip_if_fewer_comments_than (listings come with the number of comments).
+# This way, unwanted posts are left out before being downloaded.
+def listed_post_passes_filters?(post, settings)
+    if post['num_comments'] != nil && post['num_comments'].to_i < (settings['skip_if_fewer_comments_than'] || 0)
+        return false
+    end
+
+    allowed_authors = settings['filters']['post_authors_allow'] || []
+    denied_authors = settings['filters']['post_authors_deny'] || []
+
+    if allowed_authors.length > 0 && !allowed_authors.include?(post['author'])
+        return false
+    end
+
+    if denied_authors.include?(post['author'])
+        return false
+    end
+
+    title = CGI.unescapeHTML(post['title'].to_s)
+    include_patterns = settings['filters']['title_include'] || []
+    exclude_patterns = settings['filters']['title_exclude'] || []
+
+    if include_patterns.length > 0 && include_patterns.none? { |pattern| title.match?(Regexp.new(pattern, Regexp::IGNORECASE)) }
+        return false
+    end
+
+    exclude_patterns.none? { |pattern| title.match?(Regexp.new(pattern, Regexp::IGNORECASE)) }
+end
+
+# Format the post body (selftext), either verbatim or as a quote (every line prefixed with ">").
+# Verbatim is the default since quoting breaks nested quotes and code blocks in some Markdown viewers.
+def format_selftext(selftext, style)
+    if selftext == nil || selftext == ""
+        return ""
+    end
+
+    # Reddit escapes &, <, and > in the payload. Code is unescaped as is, but elsewhere only quotes (>) and ampersands are, so
+    # that escaped HTML (e.g. &lt;script&gt;) isn't turned into raw HTML in the saved file.
+    in_code_block = false
+    text = selftext.split("\n", -1).map { |line|
+        if line.match?(/^\s*(```|~~~)/)
+            in_code_block = !in_code_block
+            line
+        elsif in_code_block
+            CGI.unescapeHTML(line)
+        else
+            line.split(/(`[^`]*`)/).map { |part|
+                part.match?(/\A`[^`]*`\z/) ? CGI.unescapeHTML(part) : part.gsub("&gt;", ">").gsub("&amp;", "&")
+            }.join
+        end
+    }.join("\n")
+
+    # An unterminated code fence would swallow everything after the post body, replies included.
+    if text.scan(/^ {0,3}(```|~~~)/).length.odd?
+        text += "\n```"
+    end
+
+    if style == "quoted"
+        return "> " + text.gsub(/\n/, "\n> ")
+    end
+
+    text
+end
+
+# Convert the rendered Markdown into the file_format setting. Markdown is written as is.
+# Markdown links (and images) can be written inline or as reference-style links, per link_style, numbered from first_reference.
+def format_output(content, settings, first_reference = 1)
+    if settings['file_format'] == "txt"
+        content = markdown_to_text(content, settings['txt_line_width'])
+    elsif settings['link_style'] == "reference"
+        content = markdown_links_to_references(content, first_reference)
+    end
+
+    # Replies may come with either line ending, so all of them are made the same.
+    content = content.gsub(/\r\n?/, "\n")
+    settings['newline_style'] == "crlf" ? content.gsub("\n", "\r\n") : content
+end
+
+# What goes at the very start of a saved file: a byte order mark if asked for, as some Windows tools need it to tell that the file is UTF-8.
+def output_file_prefix(settings)
+    settings['utf8_bom'] == true ? "\uFEFF" : ""
+end
+
+# Turn inline links and images, e.g. [text](url), into reference-style ones, e.g. [text][1], with all the URLs
+# listed at the end, for easier reading as plain text or on paper. Each URL gets a single number, starting from first_number
+# (e.g. to carry on after the references of a file being appended to). Code is left as is.
+def markdown_links_to_references(markdown, first_number = 1)
+    references = {}
+    in_code_block = false
+
+    lines = markdown.split("\n", -1).map { |line|
+        if line.match?(/\A\s*(```|~~~)/)
+            in_code_block = !in_code_block
+            next line
+        end
+
+        if in_code_block
+            next line
+        end
+
+        line.split(/(`[^`]*`)/).map { |part|
+            if part.match?(/\A`[^`]*`\z/)
+                next part
+            end
+
+            # Images first, so that the text of links around images (e.g. video thumbnails) no longer has parentheses in it.
+            part = part.gsub(/!\[((?:\\.|[^\]\\])*)\]\(([^)\s]+)\)/) {
+                "![#{$1}][#{references[$2] ||= references.length + first_number}]"
+            }
+            part.gsub(/(?<!!)\[((?:\\.|\[(?:\\.|[^\]\\])*\]|[^\]\\])*)\]\(([^)\s]+)\)/) {
+                "[#{$1}][#{references[$2] ||= references.length + first_number}]"
+            }
+        }.join
+    }
+
+    if references.empty?
+        return markdown
+    end
+
+    lines.join("\n").rstrip + "\n\n" + references.map { |url, number| "[#{number}]: #{url}" }.join("\n") + "\n"
+end
+
+# Convert Markdown to plain text: Markdown syntax is stripped, reply depth is shown with indentation (two spaces per level),
+# and lines are wrapped at the given width (no wrapping if nil or 0). Code blocks are kept as is.
+def markdown_to_text(markdown, line_width)
+    lines = []
+    in_code_block = false
+
+    markdown.split("\n").each do |line|
+        depth = line[/\A\t*/].length
+        line = line.sub(/\A\t*/, "")
+
+        if line.match?(/\A\s*(```|~~~)/)
+            in_code_block = !in_code_block
+            next
+        end
+
+        if in_code_block
+            lines << "  " * depth + line
+            next
+        end
+
+        # Headings and list items (reply headers)
+        line = line.sub(/\A#+ /, "").sub(/\A\* /, "")
+
+        # The subreddit icon is dropped. Other images (including video thumbnails linking to the video) are replaced by their link.
+        line = line.gsub(/<img [^>]*>\s*/, "")
+        line = line.gsub(/\[!\[(?:\\.|[^\]\\])*\]\([^)]*\)\]\(([^)]+)\)/, '[Media: \1]')
+        line = line.gsub(/!\[(?:\\.|[^\]\\])*\]\(([^)]+)\)/, '[Image: \1]')
+
+        # Links to user profiles are dropped since the username says it all. Other links are kept next to their text.
+        line = line.gsub(/\[((?:\\.|[^\]\\])*)\]\(([^)]+)\)/) {
+            link_text = $1
+            link_url = $2
+            link_text == link_url || link_url.start_with?("https://www.reddit.com/user/") ? link_text : "#{link_text} (#{link_url})"
+        }
+
+        # Emphasis, and characters escaped by escape_markdown()
+        line = line.gsub(/\*\*(.+?)\*\*/, '\1')
+        line = line.gsub(/(?<![\w\\])_(.+?)_(?!\w)/, '\1')
+        line = line.gsub(/\\([\\`*_\[\]#|<>~])/, '\1')
+
+        lines.concat(wrap_line("  " * depth, line, line_width))
+    end
+
+    lines.join("\n")
+end
+
+# Wrap a line of text at the given width (no wrapping if nil or 0), indenting each resulting line with the prefix.
+# Quote markers (">") are repeated on each line. Words longer than the width are kept whole.
+def wrap_line(prefix, text, line_width)
+    quote = text[/\A(> ?)+/] || ""
+    text = text[quote.length..-1]
+    prefix += quote
+
+    if text.strip == ""
+        return [prefix.rstrip]
+    end
+
+    if line_width == nil || line_width <= 0
+        return [prefix + text]
+    end
+
+    lines = []
+    line = ""
+    text.split(" ").each do |word|
+        if line != "" && prefix.length + line.length + 1 + word.length > line_width
+            lines << prefix + line
+            line = word
+        else
+            line = line == "" ? word : "#{line} #{word}"
+        end
+    end
+    lines << prefix + line
+
+    lines
+end
+
+# The number of milliseconds since the given time, taken from Process.clock_gettime(Process::CLOCK_MONOTONIC).
+def elapsed_ms(started_at)
+    ((Process.clock_gettime(Process::CLOCK_MONOTONIC) - started_at) * 1000).round
+end
+
+# Run the text of a post or reply through the steps listed in text_processors, in order, the same way for both.
+# Unknown steps are skipped. Code blocks and inline code are left as is, like when formatting (see format_comment_body()).
+def process_text(text, settings)
+    if text == nil || text == ""
+        return text
+    end
+
+    processors = settings['text_processors'] || DEFAULT_TEXT_PROCESSORS
+
+    # The text is split into runs of lines in and out of code blocks, so that the steps see whole paragraphs at a time.
+    segments = []
+    in_code_block = false
+    text.split("\n", -1).each_with_index do |line, index|
+        fence = line.match?(/^\s*(```|~~~)/)
+        code = in_code_block || fence
+        in_code_block = !in_code_block if fence
+        line = "\n" + line if index > 0
+
+        if segments.length > 0 && segments.last[1] == code
+            segments.last[0] += line
+        else
+            segments << [line, code]
+        end
+    end
+
+    segments.map { |segment, code|
+        if code
+            segment
+        else
+            segment.split(/(`[^`\n]*`)/).map { |part|
+                if part.match?(/\A`[^`\n]*`\z/)
+                    part
+                else
+                    processors.reduce(part) { |processed_text, name|
+                        TEXT_PROCESSORS[name] ? TEXT_PROCESSORS[name].call(processed_text, settings) : processed_text
+                    }
+                end
+            }.join
+        end
+    }.join
+end
+
+# Apply the user's own rewrites in text_replacements, in order: each has a regex "pattern" and a "replacement",
+# which can refer to the groups of the pattern (e.g. \1), and is case-sensitive unless "ignore_case" is true.
+def apply_text_replacements(text, settings)
+    (settings['text_replacements'] || []).reduce(text) { |replaced_text, rule|
+        pattern = Regexp.new(rule['pattern'].to_s, rule['ignore_case'] == true ? Regexp::IGNORECASE : nil)
+        replaced_text.gsub(pattern, rule['replacement'].to_s)
+    }
+end
+
+# Rewrite the links in the text with clean_link(), if clean_links is enabled.
+def clean_links_in(text, settings)
+    if settings['clean_links'] != true || text == nil
+        return text
+    end
+
+    text.gsub(/https?:\/\/[^\s)\]>"]+/) { |link| clean_link(link) }
+end
+
+# Resolve a link from a URL shortener to where it leads, and rewrite links to AMP pages to their canonical page.
+# Links are only resolved once per run.
+def clean_link(link)
+    unless CLEANED_LINKS.key?(link)
+        CLEANED_LINKS[link] = de_amp_link(unshorten_link(link))
+    end
+
+    CLEANED_LINKS[link]
+end
+
+# Follow the redirects of a link from a URL shortener (e.g. bit.ly or t.co), as long as they lead to another shortener.
+# The link is returned as is if it can't be resolved. Where each link redirects to is what gets recorded (see with_recording()).
+def unshorten_link(link)
+    5.times do
+        uri = URI(link)
+        unless SHORTENER_DOMAINS.include?(uri.host.to_s.sub(/^www\./, ""))
+            break
+        end
+
+        location = with_recording("HEAD #{link}") {
+            throttle(uri.host, LIMITS['media_downloads_per_minute_per_host'])
+            response = Net::HTTP.start(uri.host, uri.port, :use_ssl => uri.scheme == "https", :open_timeout => 5, :read_timeout => 5) { |http|
+                http.head(uri.request_uri, "User-Agent" => USER_AGENT)
+            }
+            response['location'].to_s
+        }
+
+        if location == ""
+            break
+        end
+
+        link = URI.join(link, location).to_s
+    end
+
+    link
+rescue => e
+    link
+end
+
+# Rewrite a link to an AMP page to the canonical page, e.g. https://www.google.com/amp/s/example.com/article to https://example.com/article.
+def de_amp_link(link)
+    # Pages served from Google's AMP cache embed the canonical link.
+    link = link.sub(/^https?:\/\/(www\.)?google\.[a-z.]+\/amp\/s\//, "https://")
+    link = link.sub(/^https?:\/\/[\w-]+\.cdn\.ampproject\.org\/[a-z]\/s\//, "https://")
+
+    # AMP versions of a page on the site itself, e.g. amp.example.com/article, example.com/article/amp, or example.com/article?amp=1
+    # The "amp." prefix is only dropped when a domain is left (e.g. not from amp.dev, which is a site of its own).
+    link = link.sub(/^(https?:\/\/)amp\.(?=[^\/?#:.]+\.[^\/?#:]+)/, '\1')
+    link = link.sub(/\/amp\/?(?=$|[?#])/, "/")
+    # The amp parameter may be anywhere in the query, e.g. ?amp=1&page=2.
+    link = link.sub(/\?([^#]*)/) {
+        params = $1.split("&").reject { |param| param.match?(/\Aamp(=1|=true)?\z/) }
+        params.empty? ? "" : "?" + params.join("&")
+    }
+
+    link
+end
+
+# Whether a link leads to one of the blocked domains (or one of their subdomains).
+def blocked_domain?(link, blocked_domains)
+    host = URI(link).host.to_s.downcase.sub(/^www\./, "")
+    blocked_domains.any? { |domain| host == domain.downcase || host.end_with?(".#{domain.downcase}") }
+rescue URI::InvalidURIError
+    false
+end
+
+# Make a link unclickable while keeping it readable, e.g. https://example.com/page becomes hxxps://example[.]com/page.
+def defang_link(link)
+    host = URI(link).host.to_s
+    link.sub(/^http/, "hxxp").sub(host, host.gsub(".", "[.]"))
+rescue URI::InvalidURIError
+    link.sub(/^http/, "hxxp")
+end
+
+# Remove (or defang, per blocked_domain_action) the links to blocked_domains in the text.
+# Removed Markdown links keep their text. Returns the new text and the number of links taken care of.
+def block_domains_in(text, settings)
+    blocked_domains = settings['blocked_domains'] || []
+    if blocked_domains.empty? || text == nil
+        return [text, 0]
+    end
+
+    defang = settings['blocked_domain_action'] == "defang"
+    count = 0
+
+    text = text.gsub(/\[([^\]]*)\]\((https?:\/\/[^\s)]+)\)|(https?:\/\/[^\s)\]>"]+)/) { |match|
+        link_text = $1
+        link = $2 || $3
+
+        if blocked_domain?(link, blocked_domains)
+            count += 1
+
+            if link_text != nil
+                defang ? "#{link_text} (#{defang_link(link)})" : link_text
+            else
+                defang ? defang_link(link) : "[link removed]"
+            end
+        else
+            match
+        end
+    }
+
+    [text, count]
+end
+
+# Run block_domains_in() on the bodies of the given replies and all of their child replies, in place.
+# Returns the number of links taken care of.
+def block_domains_in_replies(replies, settings)
+    replies.sum { |reply|
+        if reply['kind'] != "t1"
+            next 0
+        end
+
+        reply['data']['body'], count = block_domains_in(reply['data']['body'], settings)
+
+        child_replies = reply['data']['replies']
+        if child_replies != nil && child_replies != ""
+            count += block_domains_in_replies(child_replies['data']['children'], settings)
+        end
+
+        count
+    }
+end
+
+# Shorten text to at most max_length characters, ending with the omission when it had to be cut.
+# This works on grapheme clusters so that multi-byte characters and emojis are never split in half.
+def truncate(text, max_length, omission = "…")
+    graphemes = text.each_grapheme_cluster.to_a
+    if graphemes.length <= max_length
+        return text
+    end
+
+    graphemes[0...(max_length - omission.length)].join + omission
+end
+
+# A file name that is valid on Windows, macOS, and Linux alike, made from a post's URL slug: percent-encoded characters
+# (e.g. in non-English titles) are decoded and normalized (NFC, as macOS would otherwise store them decomposed),
+# characters that Windows doesn't allow (\ / : * ? " < > |, control characters, and % to avoid confusion with encoding) are replaced, and the name is
+# truncated to max_length characters, and to 200 bytes, within the 255-byte limit of most file systems.
+def sanitize_file_name(name, max_length)
+    name = URI.decode_www_form_component(name.gsub("+", "%2B")) rescue name
+    name = name.scrub("_").unicode_normalize(:nfc)
+    name = name.gsub(/[\\\/:*?\"<>|%\x00-\x1f\x7f]/, "_")
+
+    name = truncate(name, max_length, "")
+    name = name.chop while name.bytesize > 200
+
+    # Windows drops trailing dots and spaces, and doesn't allow device names.
+    name = name.sub(/[. ]+\z/, "")
+    name = "#{name}_" if WINDOWS_RESERVED_FILE_NAMES.include?(name.upcase)
+    name
+end
+
+# The deepest level of replies to save (0 being top-level replies only), or nil for unlimited.
+# A reply_depth_max of UNLIMITED_DEPTH (-1), or no value at all, means unlimited.
+def reply_depth_max(settings)
+    depth = settings['reply_depth_max']
+    if depth == nil || depth == UNLIMITED_DEPTH
+        return nil
+    end
+
+    depth
+end
+
+# Get all the child replies to a parent (top-level) reply. Child replies deeper than max_depth (if given) are left out.
+# Ruby hashes keep their insertion order, so the child replies come depth-first, in the order Reddit returned them:
+# each reply is right after its parent, followed by its own child replies. render_replies() relies on that.
+def get_replies(reply, max_depth = nil)
+    child_replies = {}
+
+    if reply['data']['replies'] != ""
+        reply['data']['replies']['data']['children'].each do |child_reply|
+            child_reply_id = child_reply['data']['id']
+            child_reply_depth = child_reply['data']['depth']
+            child_reply_body = child_reply['data']['body']
+
+            # On the web, Reddit hides a subset of replies that you'd have to manually click to see.
+            # Those replies typically have very low upvotes and are usually just spam.
+            # This script preserves that experience and skips replies that fall into that category.
+            if child_reply_body == nil || child_reply_body == ""
+                next
+            end
+
+            # Since depth only grows further down the tree, the whole subtree can be skipped.
+            if max_depth != nil && child_reply_depth > max_depth
+                next
+            end
+
+            child_replies[child_reply_id] = {
+              'depth' => child_reply_depth,
+              'child_reply' => child_reply
+            }
+
+            child_replies.merge!(get_replies(child_reply, max_depth))
+        end
+    end
+
+    child_replies
+end
+
+# The folder (relative to the save location) to save a post in per the directory_template setting, e.g. "rust/2023/01"
+# for "{subreddit}/{year}/{month}", or nil if there's no template. Variables that are empty for a post (e.g. a post
+# without flair) are filled in with "none". Each part is made safe to use as a folder name.
+def templated_directory(post_data, settings)
+    template = settings['directory_template'].to_s
+    if template == ""
+        return nil
+    end
+
+    created_at = Time.at(post_data['created_utc'].to_i)
+    variables = {
+      'subreddit' => post_data['subreddit'],
+      'author' => post_data['author'],
+      'flair' => post_data['link_flair_text'],
+      'year' => created_at.strftime("%Y"),
+      'month' => created_at.strftime("%m"),
+      'day' => created_at.strftime("%d")
+    }
+
+    template.split("/").map { |part|
+        part = part.gsub(/\{(\w+)\}/) { |match| variables.key?($1) ? (variables[$1].to_s.strip == "" ? "none" : variables[$1].to_s.strip) : match }
+        sanitize_file_name(part, MAX_FILE_NAME_LENGTH)
+    }.reject(&:empty?).join("/")
+end
+
+# Resolve the file name based on a number of rules.
+# The extension is appended to the resolved path. Pass an empty extension to resolve a folder instead.
+# A folder given by directory_template (see templated_directory()) takes the place of the subreddit folder.
+def resolve_full_path(file_name, directory, overwrite_existing_file_enabled, save_posts_by_subreddits, subreddit, extension = ".md", folder = nil)
+    # Posts on a user's profile are in "u/<username>", which Reddit internally calls "u_<username>".
+    subreddit = subreddit.sub(/^r\//, "").sub(/^u\//, "u_")
+    full_path = directory
+
+    if folder != nil
+        full_path = "#{directory}/#{folder}"
+        FileUtils.mkdir_p(full_path)
+    elsif save_posts_by_subreddits == true
         full_path = "#{directory}/#{subreddit}"
 
         unless File.directory?(full_path)
@@ -183,31 +2506,99 @@ def resolve_full_path(url, directory, overwrite_existing_file_enabled, save_post
     # If we have to use a timestamp, return early since there's no need to worry about duplicate file names.
     if file_name == nil || file_name == ""
         puts "⚠️Could not get file name from URL. Using current timestamp as file name..."
-        return "#{full_path}/reddit_no_name_#{Time.now.to_i}"
+        return "#{full_path}/reddit_no_name_#{Time.now.to_i}#{extension}"
     end
 
     full_path = "#{full_path}/#{file_name}"
     duplicates = 0
 
-    if File.exist?("#{full_path}.md")
+    if File.exist?("#{full_path}#{extension}")
         if overwrite_existing_file_enabled == true
-            puts "⚠️File with name #{file_name}.md already exists. Overwriting is enabled. Overwriting..."
-            return "#{full_path}.md"
+            puts "⚠️File with name #{file_name}#{extension} already exists. Overwriting is enabled. Overwriting..."
+            return "#{full_path}#{extension}"
         end
 
         duplicates += 1
 
-        while File.exist?("#{full_path}_#{duplicates}.md")
+        while File.exist?("#{full_path}_#{duplicates}#{extension}")
             duplicates += 1
         end
     end
 
     if duplicates > 0
-        puts "ℹ️File with name #{file_name}.md already exists. Overwriting is disabled. Renaming to #{file_name}_#{duplicates}.md...\n"
+        puts "ℹ️File with name #{file_name}#{extension} already exists. Overwriting is disabled. Renaming to #{file_name}_#{duplicates}#{extension}...\n"
         full_path = "#{full_path}_#{duplicates}"
     end
 
-    "#{full_path}.md"
+    "#{full_path}#{extension}"
+end
+
+# Escape characters that have a meaning in Markdown, so that text such as titles and usernames is rendered as is.
+# For example, "C#" would otherwise lose its "#" at the end of a heading, and "_" in usernames could turn into italics.
+def escape_markdown(text)
+    text.to_s.gsub(/([\\`*_\[\]#|<>~])/) { "\\#{$1}" }
+end
+
+# Format the body of a reply such that each *subsequent* line is indented by the given tabs (the first line is indented by the caller).
+# Fenced code blocks are kept line by line, so that neither their blank lines nor their fences get mangled.
+def format_comment_body(body, tabs)
+    lines = []
+    in_code_block = false
+
+    body.delete("\r").split("\n").each do |line|
+        if line.match?(/^\s*(```|~~~)/)
+            in_code_block = !in_code_block
+            lines << line
+            next
+        end
+
+        if in_code_block
+            # Reddit escapes &, <, and > even in code.
+            lines << CGI.unescapeHTML(line)
+            next
+        end
+
+        # Some Reddit replies have erratic new lines. This fixes that to some extent.
+        if line.strip == ""
+            if lines.length > 0 && lines.last != ""
+                lines << ""
+            end
+            next
+        end
+
+        lines << format_comment_line(line)
+    end
+
+    # An unterminated code fence would swallow all the replies after this one.
+    if in_code_block
+        lines << "```"
+    end
+
+    while lines.last == ""
+        lines.pop
+    end
+
+    lines.join("\n#{tabs}")
+end
+
+# Clean up a line of reply text outside of code blocks. Inline code (`like this`) is left as is.
+def format_comment_line(line)
+    line.split(/(`[^`]*`)/).map { |part|
+        if part.match?(/\A`[^`]*`\z/)
+            CGI.unescapeHTML(part)
+        else
+            # Properly render quotes
+            part = part.gsub(/&gt;/, ">")
+
+            # Band-aid fix for when some bots replies with signatures tend to be broken
+            part = part.gsub(/&amp;#32;/, " ")
+            part = part.gsub(/\^\[/, "[")
+            part = part.gsub(/\^\(/, "(")
+
+            # See if reply contain u/username and replace it with [username](https://www.reddit.com/user/username)
+            part.gsub(/u\/(\w+)/, '[u/\1](https://www.reddit.com/user/\1)')
+        end
+    }.join
 end
 
 def apply_filter(author, text, upvotes, filtered_keywords, filtered_authors, min_upvotes, filtered_regex, filtered_message)
@@ -223,17 +2614,493 @@ def apply_filter(author, text, upvotes, filtered_keywords, filtered_authors, min
         end
     end
 
-    filtered_regex.each do |regex|
-        if text.match(regex)
-            return filtered_message
+    filtered_regex.each do |regex|
+        if text.match(regex)
+            return filtered_message
+        end
+    end
+
+    if upvotes < min_upvotes
+        return filtered_message
+    end
+
+    text
+end
+
+# The heading level of the post title. Replies rendered as headings start one level below it.
+def heading_base_level(settings)
+    level = settings['heading_base_level'] || 2
+    level.clamp(1, 6)
+end
+
+# The Markdown that starts the header of a reply at the given depth. Replies are nested list items,
+# or headings (down to the lowest level, H6) if comments_as_headings is enabled.
+def reply_header_prefix(depth, settings)
+    if settings['comments_as_headings'] == true
+        return "#" * (heading_base_level(settings) + 1 + depth).clamp(1, 6)
+    end
+
+    "\t" * depth + "*"
+end
+
+# The indentation of the body of a reply at the given depth.
+def reply_body_indent(depth, settings)
+    if settings['comments_as_headings'] == true
+        return ""
+    end
+
+    "\t" * (depth + 1)
+end
+
+# Look up the value for the given depth in a setting keyed by depth (e.g. {"0": 0, "3": 10}), or nil if there's none.
+# Each value applies from its depth downwards, until a deeper one takes over.
+def value_for_depth(values_by_depth, depth)
+    values_by_depth ||= {}
+    key_depth = values_by_depth.keys.map(&:to_i).select { |key| key <= depth }.max
+
+    if key_depth == nil
+        return nil
+    end
+
+    values_by_depth[key_depth.to_s]
+end
+
+# The minimum number of upvotes for a reply at the given depth to be saved, per filters.min_upvotes_by_depth, or nil if there's none.
+def min_upvotes_for_depth(depth, settings)
+    value_for_depth(settings['filters']['min_upvotes_by_depth'], depth)
+end
+
+# Whether another reply at the given depth would go over max_comments or max_comments_per_depth.
+def comment_limit_reached?(depth, saved_count, saved_count_by_depth, settings)
+    max_comments = settings['max_comments']
+    if max_comments != nil && max_comments != UNLIMITED_COMMENTS && saved_count >= max_comments
+        return true
+    end
+
+    max_comments_at_depth = value_for_depth(settings['max_comments_per_depth'], depth)
+    max_comments_at_depth != nil && saved_count_by_depth[depth] >= max_comments_at_depth
+end
+
+# Whether a reply is noise per filters.noise_filter_level: low score, short, and deep in the tree (e.g. "lol" or "this").
+# Deleted replies are left to the deleted_comments setting.
+def noise_reply?(reply, settings)
+    level = NOISE_FILTER_LEVELS[settings['filters']['noise_filter_level'].to_i.clamp(0, NOISE_FILTER_LEVELS.length)]
+    if level == nil || deleted_reply?(reply)
+        return false
+    end
+
+    reply['data']['ups'].to_i <= level['max_score'] &&
+      reply['data']['body'].to_s.strip.length < level['max_length'] &&
+      reply['data']['depth'].to_i >= level['min_depth']
+end
+
+# Whether a reply has enough upvotes for its depth, and isn't noise (see noise_reply?()). Replies that don't are still kept
+# if any of their child replies does, so that notable deep replies are saved along with the replies leading to them.
+def meets_depth_threshold?(reply, settings)
+    if keep_distinguished_reply?(reply, settings)
+        return true
+    end
+
+    min_upvotes = min_upvotes_for_depth(reply['data']['depth'].to_i, settings)
+    if (min_upvotes == nil || reply['data']['ups'].to_i >= min_upvotes) && !noise_reply?(reply, settings)
+        return true
+    end
+
+    child_replies = reply['data']['replies']
+    if child_replies == nil || child_replies == ""
+        return false
+    end
+
+    child_replies['data']['children'].any? { |child_reply| child_reply['kind'] == "t1" && meets_depth_threshold?(child_reply, settings) }
+end
+
+# Whether a reply was stickied or distinguished by a moderator or admin.
+def distinguished_reply?(reply)
+    reply['data']['stickied'] == true || reply['data']['distinguished'] != nil
+end
+
+# Whether a reply is kept regardless of the filters, per always_keep_distinguished.
+def keep_distinguished_reply?(reply, settings)
+    settings['always_keep_distinguished'] == true && distinguished_reply?(reply)
+end
+
+# The badges of a stickied (📌) or distinguished ([MOD], [ADMIN]) reply.
+def reply_badges(reply)
+    badges = []
+
+    if reply['data']['stickied'] == true
+        badges << "📌"
+    end
+
+    case reply['data']['distinguished']
+    when "moderator"
+        badges << "[MOD]"
+    when "admin"
+        badges << "[ADMIN]"
+    end
+
+    badges.join(" ")
+end
+
+# A number with the thousands separator of the locale setting (e.g. "12,345" for "en", "12.345" for "de"), or as is without one.
+# Locales not in THOUSANDS_SEPARATORS fall back to their language (e.g. "en-US" to "en"), then to "en".
+def format_number(number, settings)
+    locale = settings['locale'].to_s
+    if locale == ""
+        return number.to_s
+    end
+
+    separator = THOUSANDS_SEPARATORS[locale] || THOUSANDS_SEPARATORS[locale.split(/[-_]/).first] || ","
+    number.to_s.reverse.scan(/\d{1,3}-?/).join(separator).reverse.sub(/\A-#{Regexp.escape(separator)}/, "-")
+end
+
+# A score (upvotes) as it is shown on a post or reply: abbreviated (e.g. "12k") by default, or in full per the locale setting.
+def format_score(score, settings)
+    if settings['locale'].to_s != ""
+        return format_number(score, settings)
+    end
+
+    score >= 1000 ? "#{score / 1000}k" : score
+end
+
+# The number of awards (including gold) a post or reply received.
+def awards_count(data)
+    data['total_awards_received'] || data['gilded'] || 0
+end
+
+# A badge with the number of awards a post or reply received, if show_awards is enabled and it received any.
+def awards_field(data, settings)
+    if settings['show_awards'] != true || awards_count(data) == 0
+        return ""
+    end
+
+    "🏆 #{awards_count(data)}"
+end
+
+# Whether a reply was deleted by its author or removed by the moderators.
+def deleted_reply?(reply)
+    ["[deleted]", "[removed]"].include?(reply['data']['body'])
+end
+
+# Whether a deleted reply should be left out along with its child replies, per deleted_comments.
+# With "collapse", a one-line note takes the place of the whole subtree.
+def hide_deleted_reply?(reply, settings)
+    ["skip", "collapse"].include?(settings['deleted_comments']) && deleted_reply?(reply)
+end
+
+# The note standing in for a deleted reply and its child replies when deleted_comments is "collapse", or nothing otherwise.
+def render_collapsed_reply(reply, depth, max_depth, settings)
+    if settings['deleted_comments'] != "collapse"
+        return ""
+    end
+
+    child_replies_count = get_replies(reply, max_depth).length
+    "#{reply_header_prefix(depth, settings)} _Deleted comment (#{child_replies_count} replies collapsed)_\n\n"
+end
+
+# Render a single reply (header and body) at the given depth, 0 being a top-level reply.
+def render_reply(reply, depth, op, settings)
+    author = reply['data']['author']
+
+    author_field = escape_markdown(author)
+    if author != "[deleted]"
+        author_field = "[#{escape_markdown(author)}](https://www.reddit.com/user/#{author})"
+    end
+
+    if author == op
+        author_field += " (OP)"
+    end
+
+    timestamp_utc = reply['data']['created_utc']
+    timestamp = timestamp_utc ? Time.at(timestamp_utc).strftime("%Y-%m-%d %H:%M:%S") : ""
+    # "edited" is false for replies that were never edited, and the time of the last edit otherwise.
+    edited_utc = reply['data']['edited']
+    edited = edited_utc.is_a?(Numeric) ? Time.at(edited_utc).strftime("%Y-%m-%d %H:%M") : ""
+
+    upvotes = reply['data']['ups']
+    upvotes_field = upvotes ? format_score(upvotes, settings) : ""
+    # Reddit hides the scores of recent replies in some subreddits (and in contest mode), and sends 1 instead.
+    upvotes_field = "_(score hidden)_" if reply['data']['score_hidden'] == true
+
+    # Dataview (an Obsidian plugin) reads [key:: value] as fields of the note, so that replies can be queried across saved posts.
+    inline_fields = ""
+    if settings['comment_inline_fields'] == true
+        inline_fields = "[author:: u/#{author}]#{reply['data']['score_hidden'] == true ? "" : " [score:: #{upvotes.to_i}]"} [depth:: #{depth}] [comment_id:: #{reply['data']['id']}]"
+        inline_fields += " [date:: #{Time.at(timestamp_utc).utc.strftime("%Y-%m-%dT%H:%M:%S")}]" if timestamp_utc
+    end
+
+    content = render_template('comment_header', {
+      'prefix' => reply_header_prefix(depth, settings),
+      'color' => settings['reply_depth_color_indicators'] ? COLORS[depth] : "",
+      'author' => author_field,
+      'badges' => reply_badges(reply),
+      'score' => upvotes_field,
+      'score_field' => settings['show_upvotes'] ? "⬆️ #{upvotes_field}" : "",
+      'awards' => awards_count(reply['data']),
+      'awards_field' => awards_field(reply['data'], settings),
+      'timestamp' => timestamp,
+      'timestamp_field' => settings['show_timestamp'] ? "_(#{timestamp})_" : "",
+      'edited' => edited,
+      'edited_field' => settings['show_edited'] == true && edited != "" ? "_(edited #{edited})_" : "",
+      'inline_fields' => inline_fields,
+      'depth' => depth
+    }, settings) + "\n\n"
+
+    reply_body = reply['data']['body']
+
+    # On the web, Reddit hides a subset of replies that you'd have to manually click to see.
+    # Those replies typically have very low upvotes and are usually just spam.
+    # This script preserves that experience and skips replies that fall into that category.
+    if reply_body == nil || reply_body == ""
+        return content
+    end
+
+    if reply_body == "[deleted]"
+        reply_formatted = "Comment deleted by user"
+    elsif reply_body == "[removed]"
+        reply_formatted = "Comment removed by moderators"
+    elsif keep_distinguished_reply?(reply, settings)
+        reply_formatted = format_comment_body(process_text(reply_body, settings), "")
+    else
+        # Only apply to replies and not actual post body.
+        # When applied, reply body will be replaced by user-defined filtered_message.
+        filters = settings['filters']
+        reply_formatted = format_comment_body(process_text(reply_body, settings), "")
+        reply_formatted = apply_filter(author, reply_formatted, upvotes, filters['keywords'], filters['authors'], filters['min_upvotes'], filters['regexes'], settings['filtered_message'])
+    end
+
+    # Replies recovered from the comment archive (see recover_deleted_parents()) were deleted on Reddit, and say so.
+    if reply['data']['recovered_from_archive'] == true
+        reply_formatted = "_♻️ Deleted on Reddit. Recovered from an archive:_\n\n#{reply_formatted}"
+    end
+
+    reply_formatted = render_template('comment_body', {
+      'body' => reply_formatted,
+      'author' => author_field,
+      'depth' => depth
+    }, settings)
+
+    # Have a different indentation for reply depending on its depth, for each line of the reply.
+    tabs = reply_body_indent(depth, settings)
+    content + "#{tabs}#{reply_formatted.gsub("\n", "\n#{tabs}")}\n\n"
+end
+
+# Render the replies of a post as Markdown. Child replies deeper than max_depth (if given) or reply_depth_max are left out.
+# Replies over max_comments or max_comments_per_depth are left out along with their child replies, with a note linking to the post.
+# With only_ids (e.g. the replies added since a post was saved), only those replies are rendered, at their own depth, each
+# under a line pointing to the reply it answers when that one isn't rendered.
+def render_replies(response, op, url, settings, max_depth = nil, only_ids = nil)
+    max_depth = [max_depth, reply_depth_max(settings)].compact.min
+    content = ""
+    saved_count = 0
+    saved_count_by_depth = Hash.new(0)
+    omitted_count = 0
+    replies_by_id = only_ids ? all_replies(response).map { |reply| [reply['data']['id'], reply] }.to_h : {}
+    rendered_ids = []
+
+    # The line pointing to the reply that a reply at the given depth answers, if that one isn't rendered.
+    parent_context = lambda { |child_reply, depth|
+        parent = replies_by_id[child_reply['data']['parent_id'].to_s.sub(/^t1_/, "")]
+        if only_ids == nil || parent == nil || rendered_ids.include?(parent['data']['id'])
+            next ""
+        end
+
+        rendered_ids << parent['data']['id']
+        "#{reply_header_prefix(depth - 1, settings)} _In reply to u/#{escape_markdown(parent['data']['author'].to_s)}: [see the reply](https://www.reddit.com#{parent['data']['permalink']})_\n\n"
+    }
+
+    response.each do |reply|
+        thread_start = content.length
+
+        author = reply['data']['author']
+
+        # In some cases the author field is empty in the JSON payload.
+        if author == nil || author == ""
+            next
+        end
+
+        if author == "AutoModerator" && settings['show_auto_mod_comment'] == false
+            next
+        end
+
+        unless meets_depth_threshold?(reply, settings)
+            next
+        end
+
+        new_reply = only_ids == nil || only_ids.include?(reply['data']['id'])
+
+        if hide_deleted_reply?(reply, settings)
+            content += render_collapsed_reply(reply, 0, max_depth, settings) if new_reply
+            next
+        end
+
+        if new_reply && comment_limit_reached?(0, saved_count, saved_count_by_depth, settings)
+            omitted_count += 1 + get_replies(reply, max_depth).length
+            next
+        end
+
+        if new_reply
+            content += render_reply(reply, 0, op, settings)
+            rendered_ids << reply['data']['id']
+            saved_count += 1
+            saved_count_by_depth[0] += 1
+        end
+
+        # Parent (1st-level) reply, from which we'll get all the child replies.
+        reply_body = reply['data']['body']
+        if reply_body == nil || reply_body == ""
+            next
+        end
+
+        # Child replies come depth-first, so the child replies of an omitted reply are the ones right after it that are deeper.
+        omitted_depth = nil
+        hidden_depth = nil
+
+        get_replies(reply, max_depth).each do |_, child_reply|
+            depth = child_reply['depth']
+
+            if omitted_depth != nil && depth > omitted_depth
+                omitted_count += 1
+                next
+            end
+            omitted_depth = nil
+
+            # Same goes for the child replies of a hidden deleted reply.
+            if hidden_depth != nil && depth > hidden_depth
+                next
+            end
+            hidden_depth = nil
+
+            new_child_reply = only_ids == nil || only_ids.include?(child_reply['child_reply']['data']['id'])
+
+            if hide_deleted_reply?(child_reply['child_reply'], settings)
+                content += render_collapsed_reply(child_reply['child_reply'], depth, max_depth, settings) if new_child_reply
+                hidden_depth = depth
+                next
+            end
+
+            unless meets_depth_threshold?(child_reply['child_reply'], settings)
+                next
+            end
+
+            unless new_child_reply
+                next
+            end
+
+            if comment_limit_reached?(depth, saved_count, saved_count_by_depth, settings)
+                omitted_depth = depth
+                omitted_count += 1
+                next
+            end
+
+            content += parent_context.call(child_reply['child_reply'], depth)
+            content += render_reply(child_reply['child_reply'], depth, op, settings)
+            rendered_ids << child_reply['child_reply']['data']['id']
+            saved_count += 1
+            saved_count_by_depth[depth] += 1
+        end
+
+        if settings['line_break_between_parent_replies'] == true && content.length > thread_start
+            content += "---\n\n"
+        end
+    end
+
+    if omitted_count > 0
+        content += "_#{omitted_count} more replies were not saved. [See all replies on Reddit](#{url})._\n\n"
+    end
+
+    content
+end
+
+# Render the footer recording how and when a post was saved: the version of this script, when the post was fetched,
+# the order of replies, whether the request was made logged in, the settings file and profile (if any) used, and a hash
+# identifying the settings in effect.
+def render_source_block(version, fetched_at, comment_sort, logged_in, settings_path, profile, settings_digest)
+    content = "---\n\n"
+    content += "> 🗄️ **Source**\n>\n"
+    content += "> * Saved with: [reddit-markdown](https://github.com/chauduyphanvu/reddit-markdown) #{version}\n"
+    content += "> * Fetched at: #{fetched_at.iso8601}\n"
+    content += "> * Reply sort: #{comment_sort || "subreddit default"}\n"
+    content += "> * Logged in: #{logged_in ? "yes" : "no"}\n"
+    settings_field = File.expand_path(settings_path)
+    settings_field += ", profile \"#{profile}\"" if profile
+    content += "> * Settings: #{settings_field} (SHA-256 #{settings_digest})\n\n"
+    content
+end
+
+# Render a live thread as Markdown: its title and description, followed by all of its updates in chronological order.
+def render_live_thread(live_id, settings, access_token = nil)
+    url = "https://www.reddit.com/live/#{live_id}"
+    about = download_post_json("#{url}/about", "", access_token)['data']
+
+    # Updates are listed from newest to oldest.
+    updates = get_listing(url, access_token).reverse
+
+    started_at = about['created_utc'] ? Time.at(about['created_utc']).strftime("%Y-%m-%d %H:%M:%S") : ""
+    state = about['state'] == "live" ? "🔴 Live" : "Ended"
+
+    content = "**Reddit Live** | #{state} #{settings['show_timestamp'] ? "_(started #{started_at})_" : ""}\n\n"
+    content += "#{"#" * heading_base_level(settings)} #{escape_markdown(CGI.unescapeHTML(about['title'].to_s))}\n\n"
+    content += "Original thread: [#{url}](#{url})\n\n"
+
+    description = format_selftext(about['description'], "verbatim")
+    if description != ""
+        content += "#{description}\n\n"
+    end
+
+    content += "📰 #{updates.length} updates\n\n"
+    content += "---\n\n"
+
+    updates.each do |update|
+        author = update['author']
+        author_field = author ? "[#{escape_markdown(author)}](https://www.reddit.com/user/#{author})" : "Reddit Live"
+        timestamp = update['created_utc'] ? Time.at(update['created_utc']).strftime("%Y-%m-%d %H:%M:%S") : ""
+
+        content += "* **#{author_field}** _(#{timestamp})_\n\n"
+
+        body = format_comment_body(process_text(update['body'].to_s, settings), "\t")
+
+        # Stricken updates were retracted by the thread's contributors, but are still shown (crossed out) on Reddit.
+        if update['stricken'] == true
+            body = "~~#{body}~~ _(retracted)_"
         end
+
+        content += "\t#{body}\n\n"
     end
 
-    if upvotes < min_upvotes
-        return filtered_message
+    content
+end
+
+# Fill in the {{variables}} of the template with the given name: the one set under "templates" in settings.json, or the default one.
+# Unknown variables are left as is so that typos are easy to spot.
+def render_template(name, variables, settings)
+    template = (settings['templates'] || {})[name]
+    if template == nil || template == ""
+        template = DEFAULT_TEMPLATES[name]
     end
 
-    text
+    template.gsub(/\{\{(\w+)\}\}/) { variables.key?($1) ? variables[$1].to_s : "{{#{$1}}}" }
+end
+
+# Logging in is only needed for user-scoped features (e.g. saving your saved posts, or posts from private subreddits you are a member of).
+# Everything else works without it. Once logged in, all requests are made on behalf of the user.
+auth = settings['auth'] || {}
+access_token = nil
+logged_in_at = nil
+# Logging in is tried at most once per run, whether on startup or when a request is refused (see the main loop).
+# Logging in again to renew an expired access token doesn't count.
+login_attempted = false
+
+# Replayed runs don't make any request, logging in included. Recorded responses are the same whether logged in or not.
+if (auth['login_on_startup'] == true || options[:saved]) && REPLAY_DIRECTORY == nil && network_needed
+    access_token = log_in(auth, interactive)
+    logged_in_at = Time.now
+    login_attempted = true
+
+    if access_token == nil && options[:saved]
+        puts "Saving your saved posts requires logging in. Exiting..."
+        exit
+    end
 end
 
 if urls == "demo"
@@ -245,7 +3112,7 @@ if urls == "surprise"
     puts "🔃Surprise mode enabled. Saving a random post from r/popular...\n\n"
 
     begin
-        json = download_post_json("https://www.reddit.com/r/popular")
+        json = download_post_json("https://www.reddit.com/r/popular", "", access_token)
     rescue OpenURI::HTTPError => e
         puts "❌Error downloading r/popular JSON payload: #{e.message}. Exiting..."
         exit
@@ -258,7 +3125,7 @@ if urls == "snapshot"
     puts "🔃Snapshot mode enabled. Saving all current posts from r/popular...\n\n"
 
     begin
-        json = download_post_json("https://www.reddit.com/r/popular")
+        json = download_post_json("https://www.reddit.com/r/popular", "", access_token)
     rescue OpenURI::HTTPError => e
         puts "❌Error downloading r/popular JSON payload: #{e.message}. Exiting..."
         exit
@@ -266,253 +3133,1028 @@ if urls == "snapshot"
 
     urls = ""
     json['data']['children'].each do |post|
+        next unless listed_post_passes_filters?(post['data'], settings)
+
         urls += "https://www.reddit.com" + post['data']['permalink'] + ","
     end
 end
 
-urls = urls.split(/, |,/)
-urls.each_with_index do |url, index|
-    url = url.strip
+if options[:users]
+    urls = ""
 
-    # This is a trivial check to make sure the URL is somewhat valid. It is not meant to be foolproof.
-    unless url.match(/https:\/\/www.reddit.com\/r\/\w+\/comments\/\w+\/\w+\/?/)
-        puts "❌Error: Invalid post URL: \"#{url}\". Skipping..."
-        next
+    options[:users].each do |user|
+        user = user.strip.sub(/^\/?u\//, "")
+        next if user == ""
+
+        puts "🔃User mode enabled. Saving all posts submitted by u/#{user}...\n\n"
+
+        begin
+            user_posts = get_listing("https://www.reddit.com/user/#{user}/submitted", access_token)
+        rescue OpenURI::HTTPError => e
+            puts "❌Error downloading posts submitted by u/#{user}: #{e.message}. Skipping..."
+            next
+        end
+
+        user_urls = user_posts.select { |post| listed_post_passes_filters?(post, settings) }.map { |post| "https://www.reddit.com" + post['permalink'] }
+
+        puts "ℹ️Found #{user_posts.length} posts submitted by u/#{user}, #{user_urls.length} of which match the post filters.\n\n"
+        user_urls.each do |user_url|
+            urls += user_url + ","
+        end
     end
+end
+
+# Subreddits to save posts from, either given with --subs (using the top-level settings),
+# or part of the collections given with --collections (using their own listing, limit, and filters).
+# Searches given with --search are listed the same way, with a query instead of a subreddit.
+subreddit_jobs = []
+
+(options[:subs] || []).each do |sub|
+    subreddit_jobs << { 'subreddit' => sub, 'listing' => settings['subreddit_listing'], 'limit' => settings['subreddit_post_limit'], 'filters' => settings['filters'] }
+end
+
+# Searches are sorted by newest first, so that watch mode picks up every new post mentioning something (e.g. a project name).
+(options[:search] || []).each do |query|
+    next if query.strip == ""
 
-    puts "🔃Processing post #{index + 1} of #{urls.length}..."
-    puts "#{url}"
+    subreddit_jobs << { 'query' => query.strip, 'listing' => "new", 'limit' => settings['subreddit_post_limit'], 'filters' => settings['filters'] }
+end
+
+# Subreddits can also be entered at the prompt instead of post links, e.g. r/rust, or r/rust+programming for several at once.
+if urls != nil && urls != ""
+    entries = urls.split(/, |,/)
+    entered_subs = entries.select { |entry| entry.include?("r/") && subreddit_names(entry) != nil }
 
-    # URLs that are shared from Reddit may have query parameters appended.
-    # Drop them to get a clean URL.
-    if url.include? "?utm_source"
-        url = url.split("?utm_source").first
+    entered_subs.each do |sub|
+        subreddit_jobs << { 'subreddit' => sub, 'listing' => settings['subreddit_listing'], 'limit' => settings['subreddit_post_limit'], 'filters' => settings['filters'] }
     end
+    urls = (entries - entered_subs).map { |entry| entry + "," }.join
+end
 
-    # In case we've dropped too much. This shouldn't happen.
-    if url == nil || url == ""
-        puts "❌Error: Post URL is empty. Skipping..."
+(options[:collections] || []).each do |collection_name|
+    collection = (settings['collections'] || {})[collection_name]
+    if collection == nil
+        puts "❌Error: Collection \"#{collection_name}\" not found in settings.json. Skipping..."
         next
     end
 
-    puts "\n"
-    puts "🔃Downloading post data..."
+    puts "🔃Collection mode enabled. Saving posts from collection \"#{collection_name}\"...\n\n"
+
+    (collection['subreddits'] || []).each do |sub|
+        subreddit_jobs << {
+          'subreddit' => sub,
+          'listing' => collection['listing'] || settings['subreddit_listing'],
+          'limit' => collection['limit'] || settings['subreddit_post_limit'],
+          'filters' => settings['filters'].merge(collection['filters'] || {})
+        }
+    end
+end
+
+# Get the links to the posts listed in the subreddits (or searches) of the given jobs (that pass the post filters), each followed by a comma.
+list_subreddit_posts = lambda do |jobs|
+    listed_urls = ""
+
+    jobs.each do |job|
+        if job['query'] != nil
+            puts "🔃Search mode enabled. Saving the newest posts matching \"#{job['query']}\"...\n\n"
+
+            begin
+                search_posts = get_listing("https://www.reddit.com/search", access_token, job['limit'] == -1 ? nil : job['limit'], "&q=#{URI.encode_www_form_component(job['query'])}&sort=#{job['listing']}&type=link")
+            rescue OpenURI::HTTPError => e
+                puts "❌Error searching for \"#{job['query']}\": #{e.message}. Skipping..."
+                next
+            end
+
+            job_settings = settings.merge('filters' => job['filters'])
+            search_urls = search_posts.select { |post| listed_post_passes_filters?(post, job_settings) }.map { |post| "https://www.reddit.com" + post['permalink'] }
+
+            puts "ℹ️Found #{search_posts.length} posts matching \"#{job['query']}\", #{search_urls.length} of which match the post filters.\n\n"
+            listed_urls += search_urls.map { |search_url| search_url + "," }.join
+            next
+        end
+
+        # Multireddits (e.g. "m/programming") are listed the same way as subreddits.
+        sub = listing_feed_path(job['subreddit'])
+        if sub == nil
+            puts "❌Error: Invalid subreddit: \"#{job['subreddit']}\". Skipping..."
+            next
+        end
+
+        subreddit_listing = job['listing'] || "hot"
+        subreddit_post_limit = job['limit'] == -1 ? nil : job['limit']
+
+        puts "🔃Subreddit mode enabled. Saving #{subreddit_listing} posts from #{sub}...\n\n"
+
+        begin
+            sub_posts = get_listing("https://www.reddit.com/#{sub}/#{subreddit_listing}", access_token, subreddit_post_limit)
+        rescue OpenURI::HTTPError => e
+            puts "❌Error downloading posts from #{sub}: #{e.message}. Skipping..."
+            next
+        end
+
+        job_settings = settings.merge('filters' => job['filters'])
+        sub_urls = sub_posts.select { |post| listed_post_passes_filters?(post, job_settings) }.map { |post| "https://www.reddit.com" + post['permalink'] }
+
+        puts "ℹ️Found #{sub_posts.length} posts in #{sub}, #{sub_urls.length} of which match the post filters.\n\n"
+        sub_urls.each do |sub_url|
+            listed_urls += sub_url + ","
+        end
+    end
+
+    listed_urls
+end
+
+if subreddit_jobs.length > 0
+    urls = urls.to_s + list_subreddit_posts.call(subreddit_jobs)
+end
+
+# Subreddits checked on their own schedule in watch mode, e.g. {"r/rust": "daily"}.
+schedules = settings['schedules'] || {}
+
+if options[:watch] && subreddit_jobs.length == 0 && schedules.empty?
+    puts "❌Error: Watch mode needs subreddits or searches to watch, given with --subs, --collections, or --search, or under \"schedules\" in settings.json. Exiting..."
+    exit
+end
+
+run_started_at = Time.now
+
+# Timing and size metrics of each saved post, for the run manifest.
+post_metrics = []
+
+# The outcome of each link (saved, resynced, skipped, or failed), for the run report (with --report).
+run_report = []
+
+# Subreddit icons and banners are only looked up once per subreddit.
+subreddit_image_urls = {}
+
+# The archive index maps each saved post (or comment thread) to its file, so that it can be found again on later runs.
+archive_index = load_archive_index(directory)
+
+if options[:export_site]
+    site_directory = expand_path(options[:export_site])
+    puts "🔃Exporting the saved posts to #{site_directory}...\n\n"
+    exported_posts = export_site(site_directory, archive_index, settings)
+    puts "✅#{exported_posts} saved posts exported! Point Hugo or Zola to #{site_directory} to build the site."
+    exit
+end
+
+# With --prune, the archive is trimmed down per the retention settings instead of saving posts, e.g. from a cron job
+# that keeps a mirror (see --watch) within the storage it has.
+if options[:prune]
+    puts "🔃Pruning the saved posts at #{directory}...\n\n"
+    pruned_posts, freed_bytes = prune_archive(archive_index, settings['retention'] || {}, settings)
+    save_archive_index(directory, archive_index)
+
+    if settings['write_feed'] == true
+        write_archive_feed(directory, archive_index, settings['feed_max_items'] || 50)
+    end
+
+    puts "✅#{pruned_posts} saved posts deleted, #{(freed_bytes / 1_000_000.0).round(1)} MB freed."
+    exit
+end
+
+# Maps the link of each saved post/comment to its full name, so that it can be unsaved once saved locally.
+saved_full_names = {}
+
+if options[:saved]
+    puts "🔃Saved mode enabled. Saving all posts and comments that u/#{auth['username']} has saved...\n\n"
 
-    # The entire JSON payload
     begin
-        json = download_post_json(url)
+        saved_items = get_listing("https://www.reddit.com/user/#{auth['username']}/saved", access_token)
     rescue OpenURI::HTTPError => e
-        puts "❌Error downloading post JSON payload: #{e.message}. Skipping..."
-        next
+        puts "❌Error downloading saved posts: #{e.message}. Exiting..."
+        exit
     end
 
-    if json == nil || json == ""
-        puts "❌Error: JSON payload for #{url} is empty. Skipping..."
-        next
+    puts "ℹ️Found #{saved_items.length} saved posts and comments.\n\n"
+
+    urls = ""
+    saved_items.each do |item|
+        # Saved comments link to the comment itself, so only its thread gets saved.
+        saved_url = "https://www.reddit.com" + item['permalink']
+        saved_full_names[saved_url] = item['name']
+        urls += saved_url + ","
     end
+end
 
-    # The post body and relevant metadata
-    post_info = json[0]['data']['children']
+urls = urls.to_s.split(/, |,/)
 
-    # The replies
-    response = json[1]['data']['children']
+# Check that the posts can be saved before starting, rather than failing halfway through a large batch.
+unless File.writable?(directory)
+    puts "❌Error: The save location (#{directory}) is not writable. Exiting..."
+    exit
+end
 
-    replies_count[url] = response.length + response.map { |reply|
-        # TODO: Build a hash of parent reply to child replies ONCE right here for subsequent use.
-        if reply['data']['replies'] != "" && reply['data']['replies'] != nil
-            get_replies(reply).length
-        else
-            0
-        end
-    }.sum
+# The size of the posts is only a rough guess (most posts have far less media than estimated), so running low on space is
+# only warned about. In watch mode and with the local API, the posts aren't known yet, so only the writability is checked.
+free_bytes = free_disk_space(directory)
+estimated_bytes = urls.length * (download_media_enabled == true ? ESTIMATED_POST_BYTES + ESTIMATED_MEDIA_BYTES : ESTIMATED_POST_BYTES)
+if free_bytes != nil && free_bytes < estimated_bytes
+    puts "⚠️Saving #{urls.length} posts may take up to about #{estimated_bytes / 1_000_000} MB, but only #{free_bytes / 1_000_000} MB are free at #{directory}."
+end
 
-    op = post_info[0]['data']['author']
-    subreddit = post_info[0]['data']['subreddit_name_prefixed']
-    post_timestamp_utc = post_info[0]['data']['created_utc']
-    post_timestamp = post_timestamp_utc ? Time.at(post_timestamp_utc).strftime("%Y-%m-%d %H:%M:%S") : ""
+# With --extract, the pattern that replies are matched against. Patterns that aren't valid regexes are matched as keywords.
+extract_pattern = nil
+if options[:extract]
+    begin
+        extract_pattern = Regexp.new(options[:extract], Regexp::IGNORECASE)
+    rescue RegexpError
+        extract_pattern = Regexp.new(Regexp.escape(options[:extract]), Regexp::IGNORECASE)
+    end
+end
 
-    post_upvotes = post_info[0]['data']['ups']
-    post_upvotes_field = if post_upvotes
-                             post_upvotes >= 1000 ? "#{post_upvotes / 1000}k" : post_upvotes
-                         else
-                             ""
-                         end
+# Save the given posts, one after the other. Called once for the links gathered above, and again on every poll in watch mode.
+# In watch mode, posts that fail for a reason that may go away are tried again later (see RETRY_QUEUE_FILE_NAME), rather than
+# only if they are listed again at the next check.
+retry_queue = options[:watch] ? load_retry_queue(directory) : {}
 
-    post_is_locked = post_info[0]['data']['locked']
-    lock_message = post_is_locked ? "---\n\n>🔒 **This thread has been locked by the moderators of #{subreddit}**.\n  New comments cannot be posted" : ""
+# Put a post that failed to be saved in the retry queue (in watch mode), or give up on it after RETRY_MAX_ATTEMPTS failures.
+queue_retry = lambda do |key, url, error, previous_entry|
+    unless options[:watch]
+        next
+    end
 
-    content = "**#{subreddit}** | Posted by u/#{op} #{show_upvotes_enabled ? "⬆️ #{post_upvotes_field}" : ""} #{show_timestamp ? "_(#{post_timestamp})_" : ""}\n\n"
-    content += "## #{post_info[0]['data']['title']}\n\n"
-    content += "Original post: [#{post_info[0]['data']['url']}](#{post_info[0]['data']['url']})\n\n"
-    content += lock_message + "\n\n" if lock_message != ""
+    attempts = (previous_entry || {})['attempts'].to_i + 1
+    if attempts > RETRY_MAX_ATTEMPTS
+        puts "⚠️Giving up on #{url} after #{RETRY_MAX_ATTEMPTS} failed attempts."
+    else
+        delay_minutes = [RETRY_BASE_DELAY_MINUTES * 2 ** (attempts - 1), RETRY_MAX_DELAY_MINUTES].min
+        retry_queue[key] = { 'url' => url, 'attempts' => attempts, 'error' => error, 'retry_at' => (Time.now + delay_minutes * 60).iso8601 }
+        puts "ℹ️Trying again in #{delay_minutes} minutes (attempt #{attempts + 1} of #{RETRY_MAX_ATTEMPTS + 1})."
+    end
 
-    # The post body as text, if any
-    post_text = "#{post_info[0]['data']['selftext'].gsub(/\n/, "\n> ")}"
+    save_retry_queue(directory, retry_queue)
+end
 
-    # The post body as a media, if any
-    # This will get the first one if there's only one.
-    # We currently don't support multiple medias in a single post (post body and replies will still be downloaded but medias will be ignored).
-    post_media_url = post_info[0]['data']['url_overridden_by_dest']
+save_posts = lambda do |urls|
+    urls.each_with_index do |url, index|
+        workspace = nil
+        url = url.strip
+        source_url = url
 
-    image_extensions = %w[.jpg .jpeg .png .gif]
-    youtube_domains = %w[youtube.com youtu.be]
+        # Live threads (https://www.reddit.com/live/<id>) are made of updates rather than a post with replies.
+        live_match = url.match(/^https:\/\/www\.reddit\.com\/live\/(\w+)/)
+        if live_match
+            puts "🔃Processing live thread #{index + 1} of #{urls.length}..."
+            puts "#{truncate(url, 100)}"
+            puts "🔃Downloading live thread updates..."
 
-    if post_media_url != nil && post_media_url != ""
-        if image_extensions.any? { |ext| post_media_url.include? ext }
-            content += "![#{post_info[0]['data']['title']}](#{post_media_url})\n\n"
-        else
-            # Start by supporting YouTube videos only. Also, videos won't play inline like GIFs do.
-            # We'll get the first frame and display it as an image for external clickthroughs.
-            if youtube_domains.any? { |domain| post_media_url.include? domain }
-                youtube_id = if post_media_url.include? "watch?v="
-                                 post_media_url.split("watch?v=").last
-                             else
-                                 post_media_url.split("/").last
-                             end
-                content += "[![#{post_info[0]['data']['title']}](https://img.youtube.com/vi/#{youtube_id}/0.jpg)](#{post_media_url})\n\n"
+            begin
+                content = render_live_thread(live_match[1], settings, access_token)
+            rescue OpenURI::HTTPError => e
+                puts "❌Error downloading live thread JSON payload: #{e.message}. Skipping..."
+                run_report << { 'url' => source_url, 'status' => "failed", 'error' => e.message }
+                next
             end
+
+            full_path = resolve_full_path(live_match[1], directory, overwrite_existing_file_enabled, save_posts_by_subreddits, "live", file_extension)
+            File.open(full_path, "w") { |file| file.write(output_file_prefix(settings) + format_output(content, settings)) }
+
+            puts "✅Reddit live thread saved! Check it out at #{full_path}."
+            run_report << { 'url' => source_url, 'status' => "saved", 'path' => full_path, 'bytes_written' => File.size(full_path), 'media_count' => 0 }
+            puts "\n---\n"
+            next
         end
-    end
 
-    if post_text != nil && post_text != ""
-        content += "> #{post_text}\n\n"
-    end
+        # This is a trivial check to make sure the URL is somewhat valid. It is not meant to be foolproof.
+        # Posts are either in a subreddit (/r/<subreddit>/comments/...) or on a user's profile (/user/<username>/comments/...).
+        unless url.match(/https:\/\/www.reddit.com\/(r|u|user)\/[\w-]+\/comments\/\w+\/\w+\/?/)
+            puts "❌Error: Invalid post URL: \"#{url}\". Skipping..."
+            run_report << { 'url' => source_url, 'status' => "failed", 'error' => "Invalid post URL" }
+            next
+        end
 
-    content += "💬 ~ #{replies_count[url]} replies\n\n"
-    content += "---\n\n"
+        puts "🔃Processing post #{index + 1} of #{urls.length}..."
+        emit_event("post_started", { 'url' => url, 'index' => index + 1, 'total' => urls.length })
+        puts "#{truncate(url, 100)}"
 
-    response[0...response.length].each do |reply|
-        author = reply['data']['author']
+        # URLs that are shared from Reddit may have query parameters appended.
+        # Drop them to get a clean URL.
+        if url.include? "?utm_source"
+            url = url.split("?utm_source").first
+        end
 
-        # In some cases the author field is empty in the JSON payload.
-        if author == nil || author == ""
+        # In case we've dropped too much. This shouldn't happen.
+        if url == nil || url == ""
+            puts "❌Error: Post URL is empty. Skipping..."
+            run_report << { 'url' => source_url, 'status' => "failed", 'error' => "Post URL is empty" }
+            emit_event("post_finished", { 'url' => source_url, 'status' => "failed", 'error' => "Post URL is empty" })
+            next
+        end
+
+        # Comment permalinks have the comment ID appended after the post title, e.g.
+        # https://www.reddit.com/r/pcmasterrace/comments/101kjyq/my_dad_has_been_playing_civilization_almost_daily/j2oyv3u/
+        # For those, only the linked comment and its child replies are rendered (plus the post header).
+        comment_id = nil
+        permalink_match = url.match(/^(https:\/\/www\.reddit\.com\/(?:r|u|user)\/[\w-]+\/comments\/\w+\/\w+)\/(\w+)\/?$/)
+        if permalink_match
+            url = "#{permalink_match[1]}/"
+            comment_id = permalink_match[2]
+            puts "ℹ️Comment permalink detected. Only the thread of comment #{comment_id} will be saved."
+        end
+
+        # With --skip-existing, posts in the archive index whose file is still there aren't downloaded again.
+        post_id = url[/\/comments\/(\w+)/, 1]
+        index_key = comment_id ? "#{post_id}_#{comment_id}" : post_id
+        # Put back in the retry queue below if it fails again.
+        retry_entry = retry_queue.delete(index_key)
+        if options[:skip_existing] && !options[:force] && archive_index[index_key] != nil && File.exist?(archive_index[index_key]['path'])
+            puts "ℹ️Already saved at #{archive_index[index_key]['path']}. Skipping..."
+            puts "\n---\n"
+            run_report << { 'url' => source_url, 'status' => "skipped", 'path' => archive_index[index_key]['path'], 'error' => "Already saved" }
+            emit_event("post_finished", { 'url' => source_url, 'status' => "skipped", 'path' => archive_index[index_key]['path'], 'error' => "Already saved" })
+            next
+        end
+
+        puts "\n"
+        puts "🔃Downloading post data..."
+
+        query_params = []
+        query_params << "comment=#{comment_id}" if comment_id
+        query_params << "sort=#{comment_sort}" if comment_sort
+        query = query_params.empty? ? "" : "?" + query_params.join("&")
+
+        # The entire JSON payload
+        fetch_started_at = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+        fetched_at = Time.now
+        token_renewed = false
+        begin
+            json = download_post_json(url, query, access_token)
+        rescue OpenURI::HTTPError => e
+            # Reddit refuses some requests when not logged in (e.g. posts from quarantined subreddits, or when rate limited).
+            # If credentials are set but login_on_startup is disabled, log in and try once more.
+            if access_token == nil && !login_attempted && REPLAY_DIRECTORY == nil && ["403", "429"].include?(e.io.status[0]) && credentials_set?(auth)
+                puts "⚠️Reddit answered with #{e.message}. Logging in and trying again..."
+                login_attempted = true
+                access_token = log_in(auth, interactive)
+                logged_in_at = Time.now
+                retry if access_token != nil
+            end
+
+            # A 401 once logged in means that the access token expired (e.g. in a long run with the local API).
+            if access_token != nil && !token_renewed && e.io.status[0] == "401"
+                puts "⚠️Reddit answered with #{e.message}. Logging in again and trying again..."
+                token_renewed = true
+                access_token = log_in(auth, interactive)
+                logged_in_at = Time.now
+                retry if access_token != nil
+            end
+
+            puts "❌Error downloading post JSON payload: #{e.message}. Skipping..."
+            notify_webhooks({ 'status' => "failed", 'url' => source_url, 'error' => e.message }, settings)
+            run_report << { 'url' => source_url, 'status' => "failed", 'error' => e.message }
+            emit_event("post_finished", { 'url' => source_url, 'status' => "failed", 'error' => e.message })
+            queue_retry.call(index_key, source_url, e.message, retry_entry) if options[:watch] && retryable_http_error?(e)
+            next
+        rescue *TRANSIENT_NETWORK_ERRORS => e
+            puts "❌Error downloading post JSON payload: #{e.message}. Skipping..."
+            notify_webhooks({ 'status' => "failed", 'url' => source_url, 'error' => e.message }, settings)
+            run_report << { 'url' => source_url, 'status' => "failed", 'error' => e.message }
+            emit_event("post_finished", { 'url' => source_url, 'status' => "failed", 'error' => e.message })
+            queue_retry.call(index_key, source_url, e.message, retry_entry)
             next
         end
+        fetch_ms = elapsed_ms(fetch_started_at)
+        render_started_at = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+        media_ms = 0
 
-        if author == "AutoModerator" && show_auto_mod_comment == false
+        if json == nil || json == ""
+            puts "❌Error: JSON payload for #{url} is empty. Skipping..."
+            run_report << { 'url' => source_url, 'status' => "failed", 'error' => "JSON payload is empty" }
+            emit_event("post_finished", { 'url' => source_url, 'status' => "failed", 'error' => "JSON payload is empty" })
             next
         end
 
-        author_field = author
-        if author != "[deleted]"
-            author_field = "[#{author}](https://www.reddit.com/user/#{author})"
+        # The post body and relevant metadata
+        post_info = json[0]['data']['children']
+
+        # The replies
+        response = json[1]['data']['children']
+
+        # Posts with (almost) no discussion are usually not worth saving when archiving in bulk. Posts found in listings were
+        # already checked (see listed_post_passes_filters?()), but not the ones linked to directly.
+        min_comments = settings['skip_if_fewer_comments_than'] || 0
+        if post_info[0]['data']['num_comments'].to_i < min_comments
+            puts "ℹ️Post has fewer than #{min_comments} comments (skip_if_fewer_comments_than). Skipping..."
+            puts "\n---\n"
+            run_report << { 'url' => source_url, 'status' => "skipped", 'error' => "Fewer than #{min_comments} comments" }
+            emit_event("post_finished", { 'url' => source_url, 'status' => "skipped", 'error' => "Fewer than #{min_comments} comments" })
+            next
         end
 
-        if author == op
-            author_field += " (OP)"
+        if settings['recover_deleted_parents'] == true
+            begin
+                recovered_count = recover_deleted_parents(response)
+                puts "ℹ️#{recovered_count} deleted replies with child replies recovered from the comment archive." if recovered_count > 0
+            rescue => e
+                puts "⚠️Could not look up deleted replies in the comment archive: #{e.message}. Keeping them as deleted..."
+            end
         end
 
-        timestamp_utc = reply['data']['created_utc']
-        timestamp = timestamp_utc ? Time.at(timestamp_utc).strftime("%Y-%m-%d %H:%M:%S") : ""
-        upvotes = reply['data']['ups']
-        upvotes_field = if upvotes
-                            upvotes >= 1000 ? "#{upvotes / 1000}k" : upvotes
-                        else
-                            ""
-                        end
+        # Links to blocked domains are taken care of in the payload itself, so that every part of the saved file is covered.
+        blocked_links_count = 0
+        if (settings['blocked_domains'] || []).length > 0
+            post_info[0]['data']['selftext'], blocked_links_count = block_domains_in(post_info[0]['data']['selftext'], settings)
+            blocked_links_count += block_domains_in_replies(response, settings)
 
-        content += "* #{reply_depth_color_indicators_enabled ? colors[0] : ""} **#{author_field}** #{show_upvotes_enabled ? "⬆️ #{upvotes_field}" : ""} #{show_timestamp ? "_(#{timestamp})_" : ""}\n\n"
+            if blocked_links_count > 0
+                puts "ℹ️#{blocked_links_count} link(s) to blocked domains #{settings['blocked_domain_action'] == "defang" ? "defanged" : "removed"}."
+            end
+        end
 
-        # Parent (1st-level) reply, from which we'll get all the child replies.
-        reply_body = reply['data']['body']
+        replies_count[url] = response.length + response.map { |reply|
+            # TODO: Build a hash of parent reply to child replies ONCE right here for subsequent use.
+            if reply['data']['replies'] != "" && reply['data']['replies'] != nil
+                get_replies(reply, reply_depth_max(settings)).length
+            else
+                0
+            end
+        }.sum
 
-        # On the web, Reddit hides a subset of child replies that you'd have to manually click to see.
-        # Those child replies typically have very low upvotes and are usually just spam.
-        # This script preserves that experience and skips child replies that fall into that category.
-        if reply_body == nil || reply_body == ""
+        puts "📝#{truncate(post_info[0]['data']['title'].to_s, 80)}"
+
+        op = post_info[0]['data']['author']
+        subreddit = post_info[0]['data']['subreddit_name_prefixed']
+        post_timestamp_utc = post_info[0]['data']['created_utc']
+
+        reply_ids = all_replies(response).map { |reply| reply['data']['id'] }
+        indexed_post = archive_index[index_key]
+
+        # Posts saved before get the replies added since then appended in a dated section, rather than being saved again.
+        if settings['resync_existing_posts'] == true && !options[:force] && indexed_post != nil && File.exist?(indexed_post['path'])
+            resync_path = summary_and_full_files_enabled == true ? "#{indexed_post['path']}/full#{file_extension}" : indexed_post['path']
+            new_replies = all_replies(response).reject { |reply| indexed_post['reply_ids'].include?(reply['data']['id']) }
+
+            if new_replies.empty? || !File.exist?(resync_path)
+                puts "ℹ️Already saved at #{indexed_post['path']}, with no new replies since. Skipping..."
+            else
+                delta = "#{"#" * heading_base_level(settings)} 🆕 #{new_replies.length} new replies (#{Time.now.strftime("%Y-%m-%d %H:%M")})\n\n"
+                delta += render_replies(response, op, url, settings, nil, new_replies.map { |reply| reply['data']['id'] })
+
+                # With reference-style links, the references of the new replies are numbered after the ones already in the file,
+                # as only the first definition of a number counts.
+                last_reference = File.read(resync_path).scan(/^\[(\d+)\]: /).flatten.map(&:to_i).max || 0
+                File.open(resync_path, "a") { |file| file.write(format_output("\n" + delta, settings, last_reference + 1)) }
+                puts "✅#{new_replies.length} new replies added to #{resync_path}."
+            end
+            emit_event("post_finished", { 'url' => source_url, 'status' => (new_replies.empty? ? "skipped" : "resynced"), 'path' => indexed_post['path'], 'new_replies' => new_replies.length })
+            notify_webhooks({ 'status' => "resynced", 'url' => source_url, 'title' => post_info[0]['data']['title'], 'subreddit' => subreddit, 'score' => post_info[0]['data']['ups'], 'path' => indexed_post['path'], 'new_replies' => new_replies.length }, settings)
+
+            indexed_post['reply_ids'] = reply_ids
+            indexed_post['synced_at'] = Time.now.iso8601
+            save_archive_index(directory, archive_index)
+            run_report << { 'url' => source_url, 'status' => (new_replies.empty? ? "skipped" : "resynced"), 'path' => indexed_post['path'], 'media_count' => 0 }
+
+            puts "\n---\n"
             next
         end
 
-        if reply_body == "[deleted]"
-            reply_formatted = "Comment deleted by user"
+        # Comment threads are saved next to (and not over) the full post.
+        file_name = url.split("/").last
+        file_name = sanitize_file_name(file_name, settings['max_file_name_length'] || MAX_FILE_NAME_LENGTH) if file_name
+        file_name = "#{file_name}_#{comment_id}" if comment_id && file_name
+
+        # In summary_and_full_files mode, each post gets its own folder with a summary.md (post and top-level replies only)
+        # and a full.md (the whole reply tree). Downloaded media go into a "media" folder next to the file(s).
+        if summary_and_full_files_enabled == true
+            full_path = resolve_full_path(file_name, directory, overwrite_existing_file_enabled, save_posts_by_subreddits, subreddit, "", templated_directory(post_info[0]['data'], settings))
+            media_directory = "#{full_path}/media"
         else
-            # Some Reddit replies have erratic new lines. This fixes that to some extent.
-            reply_formatted = reply_body.squeeze("\n")
-            reply_formatted = reply_formatted.squeeze("\r")
-            reply_formatted = reply_formatted.gsub(/\n/, "\n\n\t")
+            full_path = resolve_full_path(file_name, directory, overwrite_existing_file_enabled, save_posts_by_subreddits, subreddit, file_extension, templated_directory(post_info[0]['data'], settings))
+            media_directory = "#{File.dirname(full_path)}/media"
+        end
 
-            # Properly render quotes
-            reply_formatted = reply_formatted.gsub(/&gt;/, ">")
+        # Everything is saved into a workspace (a temporary folder in the save location) first, and only moved to
+        # full_path and media_directory once the post is saved whole (see publish_workspace()), so that a post that fails
+        # midway doesn't leave a half-saved folder or media behind. The workspace is removed either way.
+        workspace = Dir.mktmpdir(".reddit_markdown_", directory)
+        workspace_media_directory = "#{workspace}/media"
+        # Media saved before (e.g. when the post is saved again) are not downloaded again (see media_saved?()).
+        PUBLISHED_MEDIA_DIRECTORIES[workspace_media_directory] = media_directory
 
-            # See if reply contain u/username and replace it with [username](https://www.reddit.com/user/username)
-            reply_formatted = reply_formatted.gsub(/u\/(\w+)/, '[u/\1](https://www.reddit.com/user/\1)')
-            reply_formatted = apply_filter(author, reply_formatted, upvotes, filtered_keywords, filtered_authors, filtered_min_upvotes, filtered_regexes, filtered_message)
+        # Images and GIFs in replies
+        media_started_at = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+        localize_reply_media(response, workspace_media_directory, settings)
+        media_ms += elapsed_ms(media_started_at)
+
+        post_timestamp = post_timestamp_utc ? Time.at(post_timestamp_utc).strftime("%Y-%m-%d %H:%M:%S") : ""
+
+        post_upvotes = post_info[0]['data']['ups']
+        post_upvotes_field = post_upvotes ? format_score(post_upvotes, settings) : ""
+
+        post_is_locked = post_info[0]['data']['locked']
+        lock_message = post_is_locked ? "---\n\n>🔒 **This thread has been locked by the moderators of #{subreddit}**.\n  New comments cannot be posted" : ""
+
+        # Titles are HTML-escaped in the payload, and may contain characters that mean something in Markdown (e.g. "C#").
+        title = escape_markdown(CGI.unescapeHTML(post_info[0]['data']['title'].to_s))
+
+        subreddit_icon = ""
+        subreddit_banner = ""
+        if (show_subreddit_icon == true || show_subreddit_banner == true) && subreddit.start_with?("r/")
+            begin
+                unless subreddit_image_urls.key?(subreddit)
+                    subreddit_image_urls[subreddit] = get_subreddit_image_urls(subreddit, access_token)
+                end
+
+                icon_url, banner_url = subreddit_image_urls[subreddit]
+                if show_subreddit_icon == true && icon_url != nil
+                    media_started_at = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+                    icon_extension = File.extname(URI(icon_url).path)
+                    icon_file_name = "#{subreddit.sub("r/", "")}_icon#{icon_extension == "" ? ".png" : icon_extension}"
+                    # Icons are shared by the posts of a subreddit, so they are only downloaded once.
+                    download_media(icon_url, workspace_media_directory, icon_file_name, settings)
+                    media_ms += elapsed_ms(media_started_at)
+                    subreddit_icon = "<img src=\"media/#{icon_file_name}\" alt=\"#{subreddit}\" width=\"32\" height=\"32\" /> "
+                end
+
+                # Banners too, and they go above the rest of the header.
+                if show_subreddit_banner == true && banner_url != nil
+                    media_started_at = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+                    banner_extension = File.extname(URI(banner_url).path)
+                    banner_file_name = "#{subreddit.sub("r/", "")}_banner#{banner_extension == "" ? ".png" : banner_extension}"
+                    download_media(banner_url, workspace_media_directory, banner_file_name, settings)
+                    media_ms += elapsed_ms(media_started_at)
+                    subreddit_banner = "![#{subreddit} banner](media/#{banner_file_name})\n\n"
+                end
+            rescue => e
+                puts "⚠️Could not download the icon or banner of #{subreddit}: #{e.message}. Continuing without them..."
+            end
+        end
+
+        content = render_template('post_header', {
+          'subreddit_icon' => subreddit_icon,
+          'subreddit_banner' => subreddit_banner,
+          'subreddit' => escape_markdown(subreddit),
+          'author' => escape_markdown(op),
+          'score' => post_upvotes_field,
+          'score_field' => show_upvotes_enabled ? "⬆️ #{post_upvotes_field}" : "",
+          'awards' => awards_count(post_info[0]['data']),
+          'awards_field' => awards_field(post_info[0]['data'], settings),
+          'timestamp' => post_timestamp,
+          'timestamp_field' => show_timestamp ? "_(#{post_timestamp})_" : "",
+          'heading' => "#" * heading_base_level(settings),
+          'title' => title,
+          'url' => clean_links_in(post_info[0]['data']['url'], settings)
+        }, settings) + "\n\n"
+        content += lock_message + "\n\n" if lock_message != ""
+
+        # In contest mode, Reddit shows the replies in random order, with their scores hidden.
+        if post_info[0]['data']['contest_mode'] == true
+            content += ">🎲 **Contest mode**: replies are in random order, and their scores are hidden.\n\n"
         end
 
-        content += "\t#{reply_formatted}\n\n"
+        # The post body as text, if any
+        post_text = format_selftext(process_text(post_info[0]['data']['selftext'], settings), selftext_style)
+
+        # The post body as a media, if any
+        # Gallery posts have all of their images rendered, in order. Other posts have a single media, if any.
+        # Like the rest of the payload, the URL is HTML-escaped, and Reddit answers with a 403 to the escaped version.
+        post_media_url = post_info[0]['data']['url_overridden_by_dest']
+        post_media_url = CGI.unescapeHTML(post_media_url) if post_media_url
+        media_content = ""
+        post_id = post_info[0]['data']['id']
+        gallery_images = post_info[0]['data']['is_gallery'] == true ? get_gallery_images(post_info[0]['data']) : []
 
-        child_replies = get_replies(reply)
+        image_extensions = %w[.jpg .jpeg .png .gif]
+        youtube_domains = %w[youtube.com youtu.be]
 
-        child_replies.each do |_, child_reply|
-            content += "\t" * child_reply['depth']
-            author = child_reply['child_reply']['data']['author']
+        if gallery_images.length > 0
+            gallery_images.each_with_index do |image, image_index|
+                image_path = image['url']
 
-            author_field = author
-            if author != "[deleted]"
-                author_field = "[#{author}](https://www.reddit.com/user/#{author})"
+                if download_media_enabled == true
+                    media_started_at = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+                    image_path = local_media_path(image['url'], workspace_media_directory, "#{post_id}_#{image_index + 1}#{File.extname(URI(image['url']).path)}", settings)
+                    media_ms += elapsed_ms(media_started_at)
+                    image['local_path'] = image_path if image_path != image['url']
+                end
+
+                if image['caption'] != ""
+                    media_content += "![#{escape_markdown(image['caption'])}](#{image_path})\n\n"
+                    media_content += "_#{escape_markdown(image['caption'])}_\n\n"
+                else
+                    media_content += "![#{title} (#{image_index + 1}/#{gallery_images.length})](#{image_path})\n\n"
+                end
             end
+        elsif post_media_url != nil && post_media_url != ""
+            # Imgur's GIFVs are videos, despite the extension.
+            if image_extensions.any? { |ext| post_media_url.include? ext } && !post_media_url.include?(".gifv")
+                image_path = post_media_url
+
+                if download_media_enabled == true
+                    media_started_at = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+                    image_path = local_media_path(post_media_url, workspace_media_directory, "#{post_id}#{File.extname(URI(post_media_url).path)}", settings)
+                    media_ms += elapsed_ms(media_started_at)
+                end
+
+                media_content += "![#{title}](#{image_path})\n\n"
+            else
+                embed_info = post_info[0]['data']['secure_media'] || post_info[0]['data']['media']
+                reddit_video = embed_info ? embed_info['reddit_video'] : nil
+
+                # Media on other hosts (e.g. Imgur, Streamable) are only looked up when they are to be downloaded.
+                external_media = []
+                if download_media_enabled == true && reddit_video == nil
+                    begin
+                        external_media = resolve_external_media(post_media_url, settings)
+                    rescue => e
+                        puts "⚠️Could not look up the media at #{post_media_url}: #{e.message}. Linking to it instead..."
+                    end
+                end
+
+                # Start by supporting YouTube videos only. Also, videos won't play inline like GIFs do.
+                # We'll get the first frame and display it as an image for external clickthroughs.
+                if reddit_video != nil
+                    # Videos hosted on Reddit (v.redd.it) are linked to from their preview image, if any.
+                    video_path = post_media_url
 
-            if author == op
-                author_field += " (OP)"
+                    if download_media_enabled == true
+                        media_started_at = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+                        begin
+                            video_path = "media/#{download_reddit_video(reddit_video, workspace_media_directory, post_id, settings)}"
+                        rescue => e
+                            puts "⚠️Could not download #{post_media_url}: #{e.message}. Linking to it instead..."
+                        end
+                        media_ms += elapsed_ms(media_started_at)
+                    end
+
+                    preview_images = post_info[0]['data']['preview'] ? post_info[0]['data']['preview']['images'] : nil
+                    if preview_images != nil && preview_images.length > 0
+                        media_content += "[![#{title}](#{CGI.unescapeHTML(preview_images[0]['source']['url'])})](#{video_path})\n\n"
+                    else
+                        media_content += "[▶️ #{title}](#{video_path})\n\n"
+                    end
+                elsif external_media.length > 0
+                    external_media.each_with_index do |media, media_index|
+                        media_started_at = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+                        suffix = external_media.length > 1 ? "_#{media_index + 1}" : ""
+                        media_path = local_media_path(media['url'], workspace_media_directory, "#{post_id}#{suffix}#{media['extension']}", settings)
+                        media_ms += elapsed_ms(media_started_at)
+
+                        if media['extension'] == ".mp4"
+                            media_content += "[▶️ #{title}#{suffix == "" ? "" : " (#{media_index + 1}/#{external_media.length})"}](#{media_path})\n\n"
+                        else
+                            media_content += "![#{title}#{suffix == "" ? "" : " (#{media_index + 1}/#{external_media.length})"}](#{media_path})\n\n"
+                        end
+                    end
+                elsif youtube_domains.any? { |domain| post_media_url.include? domain }
+                    youtube_id = if post_media_url.include? "watch?v="
+                                     post_media_url.split("watch?v=").last
+                                 else
+                                     post_media_url.split("/").last
+                                 end
+                    media_content += "[![#{title}](https://img.youtube.com/vi/#{youtube_id}/0.jpg)](#{post_media_url})\n\n"
+                elsif show_embed_thumbnails_enabled == true
+                    # Other embedded players (e.g. Twitch, Vimeo, Streamable) can't be rendered in Markdown either.
+                    # Use the thumbnail provided by their embed info instead, if any.
+                    oembed = embed_info ? embed_info['oembed'] : nil
+
+                    if oembed != nil && oembed['thumbnail_url'] != nil && oembed['thumbnail_url'] != ""
+                        media_content += "[![#{title}](#{CGI.unescapeHTML(oembed['thumbnail_url'])})](#{post_media_url})\n\n"
+                    end
+                end
             end
+        end
+
+        post_body = ""
+        if post_text != nil && post_text != ""
+            post_body = render_template('post_body', { 'selftext' => post_text, 'title' => title, 'author' => escape_markdown(op) }, settings) + "\n\n"
+        end
 
-            timestamp_utc = child_reply['child_reply']['data']['created_utc']
-            timestamp = timestamp_utc ? Time.at(timestamp_utc).strftime("%Y-%m-%d %H:%M:%S") : ""
-            upvotes = child_reply['child_reply']['data']['ups']
-            upvotes_field = if upvotes
-                                upvotes >= 1000 ? "#{upvotes / 1000}k" : upvotes
-                            else
-                                ""
-                            end
+        # Posts with both a media and a text read best in the order OP intended, which varies from post to post.
+        if settings['media_position'] == "after_selftext"
+            content += post_body + media_content
+        else
+            content += media_content + post_body
+        end
 
-            content += "* #{reply_depth_color_indicators_enabled ? colors[child_reply['depth']] : ""} **#{author_field}** #{show_upvotes_enabled ? "⬆️ #{upvotes_field}" : ""} #{show_timestamp ? "_(#{timestamp})_" : ""}\n\n"
+        # For media posts, OP often adds context (e.g. the source) in a comment, which is worth having right under the post.
+        # The comment is still rendered with its child replies among the other replies.
+        if hoist_op_comment_enabled == true
+            op_comment = response.find { |reply| reply['data']['author'] == op && reply['data']['body'] != nil && reply['data']['body'] != "" }
 
-            # Have a different indentation for child reply depending on its depth.
-            tabs = "\t"
-            child_reply['depth'].times do |_|
-                tabs += "\t"
+            if op_comment != nil
+                content += "> 💬 **OP's comment**\n>\n"
+                content += "> #{format_comment_body(process_text(op_comment['data']['body'], settings), "> ")}\n\n"
             end
+        end
+
+        content += "💬 ~ #{format_number(replies_count[url], settings)} replies\n\n"
+
+        if comment_id
+            content += "> 🧵 Showing a single comment thread. [View all replies](#{url})\n\n"
+        end
+        content += "---\n\n"
+
+        # The path and content of each file to save
+        outputs = {}
 
-            child_reply_body = child_reply['child_reply']['data']['body']
+        # How and when the post was saved, for research archives that need to record their provenance.
+        source_block = ""
+        if settings['show_source_block'] == true
+            source_block = render_source_block(version, fetched_at, comment_sort, access_token != nil, settings_path, options[:profile], settings_digest)
+        end
+
+        if summary_and_full_files_enabled == true
+            outputs["#{full_path}/summary#{file_extension}"] = output_file_prefix(settings) + format_output(content + render_replies(response, op, url, settings, 0) + source_block + "\n", settings)
+            outputs["#{full_path}/full#{file_extension}"] = output_file_prefix(settings) + format_output(content + render_replies(response, op, url, settings) + source_block + "\n", settings)
+        else
+            content += render_replies(response, op, url, settings)
+            content += source_block
+            content += "\n"
+
+            outputs[full_path] = output_file_prefix(settings) + format_output(content, settings)
+        end
+
+        outputs = outputs.map { |output_path, output| [output_path, link_archived_posts(output, output_path, archive_index, index_key, settings)] }.to_h
 
-            if child_reply_body == "[deleted]"
-                child_reply_formatted = "Comment deleted by user"
+        # With --extract, the matching replies are also listed on their own, next to the saved file(s).
+        if extract_pattern != nil
+            extracts = render_extracts(response, extract_pattern, post_info[0]['data']['title'], settings)
+            if extracts != nil
+                extracts_path = summary_and_full_files_enabled == true ? "#{full_path}/extracts#{file_extension}" : full_path.chomp(file_extension) + "_extracts#{file_extension}"
+                outputs[extracts_path] = output_file_prefix(settings) + format_output(extracts, settings)
             else
-                # Format the child reply body such that each *subsequent new line* is indented by the depth of the reply.
-                # Some Reddit replies have erratic new lines. This fixes that to some extent.
-                child_reply_formatted = child_reply_body.gsub(/\n/, "\n#{tabs}")
+                puts "ℹ️No replies match #{extract_pattern.source}."
+            end
+        end
+
+        # A listing of the images of a gallery post, so that they can be used independently of the post.
+        if gallery_index_enabled == true && gallery_images.length > 0
+            gallery_index_path = summary_and_full_files_enabled == true ? "#{full_path}/gallery.json" : full_path.chomp(file_extension) + "_gallery.json"
+            gallery_index = gallery_images.each_with_index.map { |image, image_index|
+                {
+                  'index' => image_index + 1,
+                  'caption' => image['caption'],
+                  'width' => image['width'],
+                  'height' => image['height'],
+                  'url' => image['url'],
+                  'local_path' => image['local_path']
+                }
+            }
+
+            outputs[gallery_index_path] = JSON.pretty_generate(gallery_index)
+        end
+
+        render_ms = elapsed_ms(render_started_at) - media_ms
+
+        puts "🔃Saving...\n"
+
+        media_paths = Dir.glob("#{workspace}/media/*")
+        bytes_written = outputs.values.map(&:bytesize).sum + media_paths.map { |media_path| File.size(media_path) }.sum
+
+        publish_workspace(workspace, outputs, media_directory)
 
-                # Properly render quotes
-                child_reply_formatted = child_reply_formatted.gsub(/&gt;/, ">")
+        run_report << {
+          'url' => source_url,
+          'status' => "saved",
+          'path' => full_path,
+          'bytes_written' => bytes_written,
+          'media_count' => media_paths.length
+        }
 
-                # Band-aid fix for when some bots replies with signatures tend to be broken
-                child_reply_formatted = child_reply_formatted.gsub(/&amp;#32;/, " ")
-                child_reply_formatted = child_reply_formatted.gsub(/\^\[/, "[")
-                child_reply_formatted = child_reply_formatted.gsub(/\^\(/, "(")
+        puts "✅Reddit post saved! Check it out at #{full_path}."
 
-                # See if reply contain u/username and replace it with [username](https://www.reddit.com/user/username)
-                child_reply_formatted = child_reply_formatted.gsub(/u\/(\w+)/, '[u/\1](https://www.reddit.com/user/\1)')
-                child_reply_formatted = apply_filter(author, child_reply_formatted, upvotes, filtered_keywords, filtered_authors, filtered_min_upvotes, filtered_regexes, filtered_message)
+        archive_index[index_key] = {
+          'url' => source_url,
+          'path' => full_path,
+          'title' => post_info[0]['data']['title'],
+          'subreddit' => subreddit,
+          'score' => post_info[0]['data']['ups'],
+          'saved_at' => Time.now.iso8601,
+          'reply_ids' => reply_ids
+        }
+        save_archive_index(directory, archive_index)
+
+        if settings['write_feed'] == true
+            write_archive_feed(directory, archive_index, settings['feed_max_items'] || 50)
+        end
+
+        # With site_export_location, the post is also added to the static site export as it is saved (see export_site()).
+        if settings['site_export_location'].to_s != ""
+            export_site_page("#{settings['site_export_location']}/content", index_key, archive_index[index_key], settings)
+        end
+
+        if settings['anki_export'] == true && question_post?(post_info[0]['data'])
+            card = anki_card(post_info[0]['data'], response, settings['anki_answers'] || 3, source_url)
+            if card != nil
+                append_anki_card(directory, card)
+                puts "ℹ️Flashcard added to #{directory}/#{ANKI_DECK_FILE_NAME}."
+            end
+        end
+
+        if settings['archive_bundle'] == true
+            bundle_path = (summary_and_full_files_enabled == true ? full_path : full_path.chomp(file_extension)) + ".tar.gz"
+
+            begin
+                write_archive_bundle(bundle_path, outputs, media_directory)
+                puts "ℹ️Archive bundle saved at #{bundle_path}."
+            rescue => e
+                puts "⚠️Could not save the archive bundle: #{e.message}."
             end
+        end
 
-            # The formatted child reply still needs to be indented by x number of tabs for the first line.
-            content += "#{tabs}#{child_reply_formatted}\n\n"
+        post_metrics << {
+          'url' => source_url,
+          'path' => full_path,
+          'fetch_ms' => fetch_ms,
+          'render_ms' => render_ms,
+          'media_ms' => media_ms,
+          'bytes_written' => outputs.values.map(&:bytesize).sum,
+          'replies_count' => replies_count[url],
+          'blocked_links' => blocked_links_count
+        }
+        emit_event("post_finished", { 'url' => source_url, 'status' => "saved", 'path' => full_path, 'title' => post_info[0]['data']['title'], 'subreddit' => subreddit })
+        notify_webhooks({ 'status' => "saved", 'url' => source_url, 'title' => post_info[0]['data']['title'], 'subreddit' => subreddit, 'score' => post_info[0]['data']['ups'], 'path' => full_path }, settings)
+
+        if options[:unsave] && saved_full_names[source_url] && REPLAY_DIRECTORY == nil
+            if unsave(saved_full_names[source_url], access_token)
+                puts "ℹ️Unsaved on Reddit."
+            else
+                puts "⚠️Could not unsave on Reddit. It will be saved again next time."
+            end
+        end
+        puts "\n---\n"
+    ensure
+        if workspace != nil
+            PUBLISHED_MEDIA_DIRECTORIES.delete("#{workspace}/media")
+            FileUtils.rm_rf(workspace)
         end
+    end
 
-        if line_break_enabled == true
-            content += "---\n\n"
+    # Posts saved from the retry queue are taken out of it.
+    save_retry_queue(directory, retry_queue) if options[:watch]
+end
+
+emit_event("run_started", { 'total' => urls.length })
+save_posts.call(urls)
+emit_event("run_finished", { 'saved' => post_metrics.length, 'duration_s' => (Time.now - run_started_at).round })
+
+# The run manifest records how long each post took to fetch, render, and download media for, and how big it is,
+# which helps finding out which posts are slow to save.
+if write_run_manifest_enabled == true
+    manifest_path = "#{directory}/reddit_markdown_run_#{run_started_at.strftime("%Y%m%d_%H%M%S")}.json"
+    manifest = {
+      'started_at' => run_started_at.iso8601,
+      'finished_at' => Time.now.iso8601,
+      'posts' => post_metrics
+    }
+
+    File.open(manifest_path, "w") { |file| file.write(JSON.pretty_generate(manifest)) }
+    puts "ℹ️Run manifest saved at #{manifest_path}.\n\n"
+end
+
+if options[:report]
+    write_run_report(options[:report], run_report)
+    puts "ℹ️Run report saved at #{options[:report]}.\n\n"
+end
+
+# In watch mode, the subreddits are checked for new posts on an interval, until the script is stopped (e.g. with Ctrl+C).
+# Posts in the archive index are skipped, so that each check only saves the posts that are new since the previous ones.
+# Scheduled subreddits are checked whenever they are due, including right away for the ones that were missed while the script wasn't running.
+# When each of them was last checked is kept next to the archive index.
+# Posts are saved one at a time, whether they come from watch mode or from the local API.
+save_lock = Mutex.new
+
+# The local API (with --serve) lets other programs (e.g. a browser extension) save posts and look up saved ones:
+#   POST /archive with {"url": "<post link>"} saves a post, and answers with its entry in the archive index.
+#   GET /status answers with the state of the script (e.g. how many posts were saved since it started).
+#   GET /archive?query=<text> answers with the saved posts whose title, subreddit, or link contain the text.
+#   POST /save-current with {"url": "<post link>"} is meant for a "save this thread" button of a browser extension:
+#   it saves the post unless it was saved already, and answers with where it is saved and a preview of the saved file.
+#   It only accepts requests from browser extensions (per their Origin header), sent as JSON.
+# It only listens on the local machine (127.0.0.1), and answers in JSON. Websites open in a browser can still send it requests,
+# so the ones that save posts must be sent as JSON, and may not come from a website (see api_save_allowed?()).
+# The archive index is copied before being looked through, as adding to it while another thread goes through it (e.g. when
+# watch mode saves a post) raises an error.
+if options[:serve]
+    server = TCPServer.new("127.0.0.1", options[:serve])
+    puts "🔃Local API listening on http://127.0.0.1:#{options[:serve]}...\n\n"
+
+    server_thread = Thread.new do
+        loop do
+            client = server.accept
+
+            begin
+                method, path, params, body, headers = read_api_request(client)
+                origin = headers['origin']
+
+                if method == "OPTIONS"
+                    write_api_response(client, "204 No Content", {}, origin)
+                elsif path == "/save-current" && !api_save_allowed?(method, headers, true)
+                    write_api_response(client, "403 Forbidden", { 'error' => "/save-current only accepts POST requests with a JSON body from a browser extension." }, origin)
+                elsif path == "/save-current"
+                    post_url = JSON.parse(body)['url'].to_s.strip
+                    entry = archive_index.dup.values.find { |indexed_post| indexed_post['url'] == post_url && File.exist?(indexed_post['path']) }
+                    already_saved = entry != nil
+
+                    unless already_saved
+                        save_lock.synchronize { save_posts.call([post_url]) }
+                        entry = archive_index.dup.values.find { |indexed_post| indexed_post['url'] == post_url }
+                    end
+
+                    if entry != nil
+                        saved_file, _ = saved_post_file_and_media_directory(entry, settings)
+                        preview = File.exist?(saved_file) ? File.read(saved_file)[0, API_PREVIEW_LENGTH] : ""
+
+                        write_api_response(client, "200 OK", {
+                          'path' => entry['path'],
+                          'title' => entry['title'],
+                          'already_saved' => already_saved,
+                          'preview' => preview
+                        }, origin)
+                    else
+                        write_api_response(client, "422 Unprocessable Entity", { 'error' => "The post could not be saved." }, origin)
+                    end
+                elsif method == "GET" && path == "/status"
+                    write_api_response(client, "200 OK", {
+                      'started_at' => run_started_at.iso8601,
+                      'saved_posts' => post_metrics.length,
+                      'archived_posts' => archive_index.length,
+                      'watching' => subreddit_jobs.map { |job| job['subreddit'] || "search: #{job['query']}" } + schedules.keys
+                    }, origin)
+                elsif method == "GET" && path == "/archive"
+                    query = params['query'].to_s.downcase
+                    matches = archive_index.dup.select { |_, entry| [entry['title'], entry['subreddit'], entry['url']].any? { |field| field.to_s.downcase.include?(query) } }
+                    write_api_response(client, "200 OK", matches.map { |key, entry| entry.reject { |name, _| name == 'reply_ids' }.merge('id' => key) }, origin)
+                elsif path == "/archive" && method == "POST" && !api_save_allowed?(method, headers, false)
+                    write_api_response(client, "403 Forbidden", { 'error' => "POST /archive only accepts JSON bodies, and not from websites." }, origin)
+                elsif method == "POST" && path == "/archive"
+                    post_url = JSON.parse(body)['url'].to_s.strip
+                    save_lock.synchronize { save_posts.call([post_url]) }
+
+                    entry = archive_index.dup.values.find { |indexed_post| indexed_post['url'] == post_url }
+                    if entry != nil
+                        write_api_response(client, "200 OK", entry.reject { |name, _| name == 'reply_ids' }, origin)
+                    else
+                        write_api_response(client, "422 Unprocessable Entity", { 'error' => "The post could not be saved." }, origin)
+                    end
+                else
+                    write_api_response(client, "404 Not Found", { 'error' => "Unknown endpoint #{method} #{path}." }, origin)
+                end
+            rescue Timeout::Error
+                puts "⚠️Local API client sent no complete request within #{API_READ_TIMEOUT} seconds. Closing the connection..."
+            rescue JSON::ParserError
+                write_api_response(client, "400 Bad Request", { 'error' => "The request body must be JSON, e.g. {\"url\": \"https://www.reddit.com/r/...\"}." }, origin)
+            rescue => e
+                puts "⚠️Local API request failed: #{e.message}."
+            ensure
+                client.close
+            end
         end
     end
+end
+
+if options[:watch]
+    schedule_state = load_schedule_state(directory)
+
+    # The subreddits given on the command line were just checked, before watch mode started.
+    first_check = true
+
+    loop do
+        # Errors (e.g. the network being down for a while) are only logged, so that watch mode keeps running.
+        begin
+            if access_token != nil && Time.now - logged_in_at >= ACCESS_TOKEN_RENEWAL_SECONDS
+                puts "🔃Logging in again, as the access token is about to expire..."
+                access_token = log_in(auth, interactive)
+                logged_in_at = Time.now
+            end
+
+            schedules.each do |sub, interval|
+                unless schedule_due?(schedule_state[sub], interval)
+                    next
+                end
 
-    content += "\n"
-    full_path = resolve_full_path(url, directory, overwrite_existing_file_enabled, save_posts_by_subreddits, subreddit)
+                puts "🔃Scheduled check of #{sub} (#{interval})...\n\n"
+                scheduled_urls = list_subreddit_posts.call([{ 'subreddit' => sub, 'listing' => settings['subreddit_listing'], 'limit' => settings['subreddit_post_limit'], 'filters' => settings['filters'] }])
+                save_lock.synchronize { save_posts.call(scheduled_urls.split(",")) }
 
-    puts "🔃Saving...\n"
+                schedule_state[sub] = Time.now.iso8601
+                save_schedule_state(directory, schedule_state)
+            end
+
+            if subreddit_jobs.length > 0 && !first_check
+                watched_urls = list_subreddit_posts.call(subreddit_jobs)
+                save_lock.synchronize { save_posts.call(watched_urls.split(",")) }
+            end
+
+            due_retries = retry_queue.values.select { |entry| Time.parse(entry['retry_at']) <= Time.now }
+            if due_retries.length > 0
+                puts "🔃Trying again to save #{due_retries.length} posts that failed before...\n\n"
+                save_lock.synchronize { save_posts.call(due_retries.map { |entry| entry['url'] }) }
+            end
+        rescue => e
+            puts "❌Error while checking for new posts: #{e.message}. Trying again at the next check..."
+        end
+        first_check = false
 
-    File.open(full_path, "w") { |file| file.write(content) }
+        puts "🔃Watch mode enabled. Checking for new posts again in #{options[:watch]} minutes...\n\n"
+        sleep(options[:watch] * 60)
+    end
+end
 
-    puts "✅Reddit post saved! Check it out at #{full_path}."
-    puts "\n---\n"
+if options[:serve]
+    server_thread.join
 end
 
 puts "Thanks for using this script!\n"